@@ -1,3 +1,395 @@
-fn main() {
-    println!("Hello, world!");
+#![allow(clippy::needless_return)]
+
+use std::{env, fs, io::{self, IsTerminal}, path::PathBuf, process::ExitCode};
+
+use data_structures::{Address, DataInterface};
+use fileio::{ByteOrder, FileIO, HexOptions};
+use translator::{diagnostics::summary, disasm::{self, RegNames}, isa, Translator};
+
+// Exit codes
+const EXIT_ASSEMBLY: u8 = 1; // Source failed to assemble, or a HEX input is malformed
+const EXIT_USAGE: u8 = 2; // Bad command line
+const EXIT_IO: u8 = 3; // Input or output file could not be accessed
+
+const USAGE: &str = "\
+Usage: assembler [OPTIONS] <INPUT>...
+       assembler --disassemble [--reg-names <NAMES>] <HEX>...
+
+Assembles one or more RISC-V source files into a single image,
+or lists the instructions of Intel HEX images.
+
+Options:
+  -o, --output <PATH>    Output file (default: first input with the format's extension)
+  -f, --format <FORMAT>  Output format: hex, bin (default: hex)
+      --record-len <N>   Data bytes per Intel HEX record: 16, 32 (default: 16)
+      --isa <ISA>        Target ISA: rv32i, rv32ic, rv64i, rv64ic (default: rv32i)
+      --isa-spec <PATH>  JSON file of extra instructions, may be repeated
+      --base <ADDR>      Address of the first section (default: 0)
+      --section-start <NAME=ADDR>
+                         Places a section at a fixed address, e.g. .data=0x80000000,
+                         may be repeated (default: each section follows the one before)
+      --entry <ENTRY>    Start address in HEX output, a symbol or an address
+                         (default: _start if it is defined, otherwise the base of .text)
+      --fill <BYTE>      Byte used for gaps in binary output, such as between sections (default: 0)
+      --error-format <F> Diagnostics format: human, json (default: human)
+  -d, --disassemble      List the instructions in Intel HEX inputs (to stdout unless -o is given)
+      --reg-names <N>    Registers in listings: numeric, abi (default: numeric)
+  -h, --help             Print this help
+
+Exit codes:
+  0  success
+  1  the source failed to assemble, or a HEX input is malformed
+  2  bad command line
+  3  an input or output file could not be accessed";
+
+#[derive(Clone, Copy)]
+enum Format {
+    Hex,
+    Bin,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Hex => "hex",
+            Format::Bin => "bin",
+        }
+    }
+}
+
+// How diagnostics are printed
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ErrorFormat {
+    Human, // Rendered with the source line, coloured on a terminal
+    Json, // One JSON object per line
+}
+
+struct Options {
+    inputs: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    format: Format,
+    hex: HexOptions,
+    isa: String,
+    isa_specs: Vec<PathBuf>,
+    base_address: Address,
+    section_bases: Vec<(String, Address)>, // Sections placed at fixed addresses
+    entry: Option<String>, // Symbol or address the HEX start record points at
+    fill: u8, // Byte written into gaps of binary output
+    error_format: ErrorFormat,
+    disassemble: bool,
+    reg_names: RegNames,
+}
+
+// Pulls the value following an option, failing if the command line ends early.
+fn option_value(args: &mut impl Iterator<Item = String>, option: &str) -> Result<String, String> {
+    return args.next().ok_or(format!("option `{option}` requires a value"));
+}
+
+// Returns None when only help was requested.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut opts = Options {
+        inputs: Vec::new(),
+        output: None,
+        format: Format::Hex,
+        hex: HexOptions::default(),
+        isa: "rv32i".to_string(),
+        isa_specs: Vec::new(),
+        base_address: 0,
+        section_bases: Vec::new(),
+        entry: None,
+        fill: 0,
+        error_format: ErrorFormat::Human,
+        disassemble: false,
+        reg_names: RegNames::Numeric,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => {
+                opts.output = Some(PathBuf::from(option_value(&mut args, &arg)?));
+            }
+            "-f" | "--format" => {
+                opts.format = match option_value(&mut args, &arg)?.as_str() {
+                    "hex" => Format::Hex,
+                    "bin" => Format::Bin,
+                    other => return Err(format!("unknown output format `{other}`")),
+                };
+            }
+            "--record-len" => {
+                opts.hex.record_len = match option_value(&mut args, &arg)?.as_str() {
+                    "16" => 16,
+                    "32" => 32,
+                    other => return Err(format!("unsupported record length `{other}`")),
+                };
+            }
+            "--isa" => {
+                opts.isa = option_value(&mut args, &arg)?;
+            }
+            "--isa-spec" => {
+                opts.isa_specs.push(PathBuf::from(option_value(&mut args, &arg)?));
+            }
+            "--base" => {
+                let value = option_value(&mut args, &arg)?;
+                opts.base_address = data_structures::str_to_address(&value)
+                    .map_err(|_| format!("invalid base address `{value}`"))?;
+            }
+            "--section-start" => {
+                let value = option_value(&mut args, &arg)?;
+                let (name, address) = value.split_once('=')
+                    .ok_or(format!("expected `NAME=ADDR`, found `{value}`"))?;
+                let address = data_structures::str_to_address(address)
+                    .map_err(|_| format!("invalid address `{address}` for section `{name}`"))?;
+                opts.section_bases.push((name.to_string(), address));
+            }
+            "--entry" => {
+                opts.entry = Some(option_value(&mut args, &arg)?);
+            }
+            "--fill" => {
+                let value = option_value(&mut args, &arg)?;
+                opts.fill = data_structures::str_to_int(&value)
+                    .ok()
+                    .and_then(|fill| u8::try_from(fill).ok())
+                    .ok_or(format!("invalid fill byte `{value}`"))?;
+            }
+            "--error-format" => {
+                opts.error_format = match option_value(&mut args, &arg)?.as_str() {
+                    "human" => ErrorFormat::Human,
+                    "json" => ErrorFormat::Json,
+                    other => return Err(format!("unknown error format `{other}`")),
+                };
+            }
+            "-d" | "--disassemble" => {
+                opts.disassemble = true;
+            }
+            "--reg-names" => {
+                opts.reg_names = match option_value(&mut args, &arg)?.as_str() {
+                    "numeric" => RegNames::Numeric,
+                    "abi" => RegNames::Abi,
+                    other => return Err(format!("unknown register names `{other}`")),
+                };
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("unknown option `{arg}`"));
+            }
+            _ => opts.inputs.push(PathBuf::from(arg)),
+        }
+    }
+
+    if opts.inputs.is_empty() {
+        return Err("no input files".to_string());
+    }
+    return Ok(Some(opts));
+}
+
+fn run(opts: &Options) -> Result<(), ExitCode> {
+    let (Some(instructions), Some((xlen, compressed))) = (isa::instructions(&opts.isa), isa::target(&opts.isa)) else {
+        eprintln!("error: unsupported ISA `{}`", opts.isa);
+        return Err(ExitCode::from(EXIT_USAGE));
+    };
+
+    let mut fio = FileIO {};
+    let mut di = DataInterface::new();
+    for input in opts.inputs.iter() {
+        if let Err(err) = fio.open_code(input, &mut di) {
+            eprintln!("error: {err}");
+            return Err(ExitCode::from(EXIT_IO));
+        }
+    }
+
+    let (result, diagnostics, entry) = {
+        let mut translator = Translator::new(64, 64, &mut di);
+        translator.initialize(&instructions, &isa::registers());
+        translator.add_pseudo(&isa::pseudo_instructions());
+        translator.set_xlen(xlen);
+        translator.set_compressed(compressed);
+        for path in opts.isa_specs.iter() {
+            let content = match fs::read_to_string(path) {
+                Ok(content) => content,
+                Err(err) => {
+                    eprintln!("error: cannot read `{}`: {err}", path.display());
+                    return Err(ExitCode::from(EXIT_IO));
+                }
+            };
+            if let Err(err) = translator.load_spec(&content) {
+                eprintln!("error: `{}`: {err}", path.display());
+                return Err(ExitCode::from(EXIT_USAGE));
+            }
+        }
+        translator.set_base_address(opts.base_address);
+        for (name, base) in opts.section_bases.iter() {
+            translator.set_section_base(name, *base);
+        }
+        let result = translator.parse_file();
+        (result, translator.diagnostics().to_vec(), translator.entry_point(opts.entry.as_deref()))
+    };
+
+    match opts.error_format {
+        ErrorFormat::Human => {
+            let color = io::stdout().is_terminal();
+            for diag in diagnostics.iter() {
+                println!("{}", diag.render(di.source_line(&diag.loc), color));
+            }
+            if let Some(summary) = summary(&diagnostics) {
+                println!("{summary}");
+            }
+        }
+        ErrorFormat::Json => {
+            for diag in diagnostics.iter() {
+                println!("{}", diag.to_json());
+            }
+        }
+    }
+    if result.is_err() {
+        return Err(ExitCode::from(EXIT_ASSEMBLY));
+    }
+
+    // Intel HEX start records are 32-bit
+    let mut hex = opts.hex.clone();
+    hex.start_address = match entry {
+        Some(entry) => Some(u32::try_from(entry).map_err(|_| {
+            eprintln!("error: entry point {entry:#X} is above the 32-bit address space");
+            ExitCode::from(EXIT_USAGE)
+        })?),
+        None => {
+            eprintln!("error: entry symbol `{}` is not defined", opts.entry.as_deref().unwrap_or_default());
+            return Err(ExitCode::from(EXIT_USAGE));
+        }
+    };
+
+    let output = opts.output.clone()
+        .unwrap_or_else(|| opts.inputs[0].with_extension(opts.format.extension()));
+    let written = match opts.format {
+        Format::Hex => fio.write_hex(&output, &mut di, &hex),
+        Format::Bin => fio.write_bin(&output, &mut di, opts.fill),
+    };
+    if let Err(err) = written {
+        eprintln!("error: cannot write `{}`: {err}", output.display());
+        return Err(ExitCode::from(EXIT_IO));
+    }
+
+    return Ok(());
+}
+
+// Lists every instruction of the HEX inputs, one per line with its address and encoding.
+fn run_disassemble(opts: &Options) -> Result<(), ExitCode> {
+    let mut di = DataInterface::new();
+    for input in opts.inputs.iter() {
+        // Files that cannot be read are an I/O error, malformed records are bad input
+        let content = match fs::read_to_string(input) {
+            Ok(content) => content,
+            Err(err) => {
+                eprintln!("error: cannot read `{}`: {err}", input.display());
+                return Err(ExitCode::from(EXIT_IO));
+            }
+        };
+        if let Err(err) = fileio::hex::read_hex(&content, &mut di, ByteOrder::Little) {
+            eprintln!("error: `{}`: {err}", input.display());
+            return Err(ExitCode::from(EXIT_ASSEMBLY));
+        }
+    }
+
+    let mut listing = String::new();
+    while let Some(node) = di.pop_parsed() {
+        let text = match node.size {
+            4 => disasm::disassemble(node.instruction, opts.reg_names),
+            _ => {
+                let bytes: Vec<String> = node.bytes().iter().map(|byte| format!("{byte:#04x}")).collect();
+                format!(".byte {}", bytes.join(", "))
+            }
+        };
+        listing.push_str(&format!("{:08x}:  {:0width$x}  {text}\n",
+            node.address, node.instruction, width = node.size as usize * 2));
+    }
+
+    match &opts.output {
+        Some(output) => {
+            if let Err(err) = fs::write(output, listing) {
+                eprintln!("error: cannot write `{}`: {err}", output.display());
+                return Err(ExitCode::from(EXIT_IO));
+            }
+        }
+        None => print!("{listing}"),
+    }
+    return Ok(());
+}
+
+fn main() -> ExitCode {
+    let opts = match parse_args(env::args().skip(1)) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(msg) => {
+            eprintln!("error: {msg}\n\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    let result = match opts.disassemble {
+        true => run_disassemble(&opts),
+        false => run(&opts),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => code,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> impl Iterator<Item = String> {
+        list.iter().map(|s| s.to_string()).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let opts = parse_args(args(&["a.s", "-o", "out.bin", "--format", "bin", "b.s", "--record-len", "32",
+            "--base", "0x80000000", "--error-format", "json", "--isa-spec", "lab.json", "--fill", "0xFF",
+            "--section-start", ".data=0x100000000", "--entry", "main"]))
+            .expect("Valid arguments rejected")
+            .expect("Help was not requested");
+        assert_eq!(opts.inputs, vec![PathBuf::from("a.s"), PathBuf::from("b.s")]);
+        assert_eq!(opts.output, Some(PathBuf::from("out.bin")));
+        assert!(matches!(opts.format, Format::Bin));
+        assert_eq!(opts.hex.record_len, 32);
+        assert_eq!(opts.isa, "rv32i");
+        assert_eq!(opts.isa_specs, vec![PathBuf::from("lab.json")]);
+        assert_eq!(opts.base_address, 0x8000_0000);
+        assert_eq!(opts.fill, 0xFF);
+        assert_eq!(opts.section_bases, vec![(".data".to_string(), 0x1_0000_0000)]);
+        assert_eq!(opts.entry.as_deref(), Some("main"));
+        assert_eq!(opts.error_format, ErrorFormat::Json);
+
+        assert!(!opts.disassemble);
+
+        let opts = parse_args(args(&["-d", "a.hex", "--reg-names", "abi"]))
+            .expect("Valid arguments rejected")
+            .expect("Help was not requested");
+        assert!(opts.disassemble);
+        assert_eq!(opts.reg_names, RegNames::Abi);
+
+        // Addresses are 64-bit
+        let opts = parse_args(args(&["a.s", "--base", "0x200000000"]))
+            .expect("Valid arguments rejected")
+            .expect("Help was not requested");
+        assert_eq!(opts.base_address, 0x2_0000_0000);
+
+        assert!(parse_args(args(&["-h"])).expect("Help rejected").is_none());
+        assert!(parse_args(args(&[])).is_err());
+        assert!(parse_args(args(&["a.s", "-o"])).is_err());
+        assert!(parse_args(args(&["a.s", "--format", "elf"])).is_err());
+        assert!(parse_args(args(&["a.s", "--bogus"])).is_err());
+        assert!(parse_args(args(&["a.s", "--record-len", "8"])).is_err());
+        assert!(parse_args(args(&["a.s", "--base", "high"])).is_err());
+        assert!(parse_args(args(&["a.s", "--fill", "256"])).is_err());
+        assert!(parse_args(args(&["a.s", "--entry"])).is_err());
+        assert!(parse_args(args(&["a.s", "--section-start", ".data"])).is_err());
+        assert!(parse_args(args(&["a.s", "--section-start", ".data=ram"])).is_err());
+        assert!(parse_args(args(&["a.s", "--error-format", "xml"])).is_err());
+        assert!(parse_args(args(&["a.hex", "--reg-names", "fancy"])).is_err());
+    }
 }
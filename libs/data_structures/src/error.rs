@@ -0,0 +1,122 @@
+use std::fmt;
+
+// Where in the source something was found. Line and column are 1-based, 0 when unknown.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            return write!(f, "{}", self.file);
+        }
+        return write!(f, "{}:{}:{}", self.file, self.line, self.column);
+    }
+}
+
+// Everything that can go wrong while assembling.
+// Each error carries where it happened and the token that caused it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssemblerError {
+    UnknownMnemonic { loc: Location, token: String },
+    UnknownDirective { loc: Location, token: String },
+    BadRegister { loc: Location, token: String },
+    ImmediateOutOfRange { loc: Location, token: String, value: i64, min: i64, max: i64 },
+    MisalignedTarget { loc: Location, token: String, offset: i64, align: i64 },
+    UndefinedLabel { loc: Location, token: String },
+    DuplicateLabel { loc: Location, token: String, previous: Location }, // Where it was first defined
+    WrongOperandCount { loc: Location, token: String, expected: usize, found: usize },
+    AddressOverflow { loc: Location, token: String, address: u64 },
+    Syntax { loc: Location, token: String, message: String },
+    Io { loc: Location, token: String, message: String },
+}
+
+impl AssemblerError {
+    pub fn location(&self) -> &Location {
+        return match self {
+            AssemblerError::UnknownMnemonic { loc, .. }
+            | AssemblerError::UnknownDirective { loc, .. }
+            | AssemblerError::BadRegister { loc, .. }
+            | AssemblerError::ImmediateOutOfRange { loc, .. }
+            | AssemblerError::MisalignedTarget { loc, .. }
+            | AssemblerError::UndefinedLabel { loc, .. }
+            | AssemblerError::DuplicateLabel { loc, .. }
+            | AssemblerError::WrongOperandCount { loc, .. }
+            | AssemblerError::AddressOverflow { loc, .. }
+            | AssemblerError::Syntax { loc, .. }
+            | AssemblerError::Io { loc, .. } => loc,
+        };
+    }
+
+    // The offending token, as written in the source.
+    pub fn token(&self) -> &str {
+        return match self {
+            AssemblerError::UnknownMnemonic { token, .. }
+            | AssemblerError::UnknownDirective { token, .. }
+            | AssemblerError::BadRegister { token, .. }
+            | AssemblerError::ImmediateOutOfRange { token, .. }
+            | AssemblerError::MisalignedTarget { token, .. }
+            | AssemblerError::UndefinedLabel { token, .. }
+            | AssemblerError::DuplicateLabel { token, .. }
+            | AssemblerError::WrongOperandCount { token, .. }
+            | AssemblerError::AddressOverflow { token, .. }
+            | AssemblerError::Syntax { token, .. }
+            | AssemblerError::Io { token, .. } => token,
+        };
+    }
+
+    // Stable identifier for tools that consume diagnostics.
+    pub fn code(&self) -> &'static str {
+        return match self {
+            AssemblerError::UnknownMnemonic { .. } => "unknown-mnemonic",
+            AssemblerError::UnknownDirective { .. } => "unknown-directive",
+            AssemblerError::BadRegister { .. } => "bad-register",
+            AssemblerError::ImmediateOutOfRange { .. } => "immediate-out-of-range",
+            AssemblerError::MisalignedTarget { .. } => "misaligned-target",
+            AssemblerError::UndefinedLabel { .. } => "undefined-label",
+            AssemblerError::DuplicateLabel { .. } => "duplicate-label",
+            AssemblerError::WrongOperandCount { .. } => "wrong-operand-count",
+            AssemblerError::AddressOverflow { .. } => "address-overflow",
+            AssemblerError::Syntax { .. } => "syntax",
+            AssemblerError::Io { .. } => "io",
+        };
+    }
+
+    // Description of the error, without its location.
+    pub fn message(&self) -> String {
+        return match self {
+            AssemblerError::UnknownMnemonic { token, .. } =>
+                format!("unknown instruction `{token}`"),
+            AssemblerError::UnknownDirective { token, .. } =>
+                format!("unknown directive `{token}`"),
+            AssemblerError::BadRegister { token, .. } =>
+                format!("`{token}` is not a register"),
+            AssemblerError::ImmediateOutOfRange { value, min, max, .. } =>
+                format!("immediate {value} is out of range, must be within {min} to {max}"),
+            AssemblerError::MisalignedTarget { offset, align, .. } =>
+                format!("offset {offset} is misaligned, targets must be {align}-byte aligned"),
+            AssemblerError::UndefinedLabel { token, .. } =>
+                format!("label `{token}` is not defined"),
+            AssemblerError::DuplicateLabel { token, .. } =>
+                format!("symbol `{token}` is already defined"),
+            AssemblerError::WrongOperandCount { token, expected, found, .. } =>
+                format!("`{token}` takes {expected} operands but {found} were given"),
+            AssemblerError::AddressOverflow { address, .. } =>
+                format!("address {address:#X} is outside the address space"),
+            AssemblerError::Syntax { message, .. } => message.clone(),
+            AssemblerError::Io { message, .. } =>
+                format!("cannot read file: {message}"),
+        };
+    }
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}: {}", self.location(), self.message());
+    }
+}
+
+impl std::error::Error for AssemblerError {}
@@ -1,9 +1,14 @@
+#![allow(clippy::needless_return)]
+
+mod error;
+
 use std::collections::LinkedList;
 use std::{cmp, io};
 
+pub use error::{AssemblerError, Location};
 
-#[derive(Clone)]
 
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
 pub enum InstType {R, I, S, B, U, J, META, PSEUDO}
 
 
@@ -14,15 +19,38 @@ pub struct Reg {
 
 #[derive(Clone)]
 pub struct Inst {
-    opcode :  u32, // Opcode field
-    funct3  : u32, // Funct3 field
-    funct7  : u32, // Funct7 field
-    rd : u32,
-    rs1 : u32,
+    pub opcode :  u32, // Opcode field
+    pub funct3  : u32, // Funct3 field, already shifted into place
+    pub funct7  : u32, // Funct7 field, already shifted into place
+    pub funct12 : u32, // Funct12 field of system instructions, already shifted into place
+    pub rd : u32,
+    pub rs1 : u32,
     
     pub inst_type : InstType // Instruction type
 }
 
+impl Inst {
+    // Builds an instruction from the raw field values, shifting funct3/funct7 into place.
+    pub fn new(inst_type : InstType, opcode : u32, funct3 : u32, funct7 : u32) -> Self {
+        Self {
+            opcode,
+            funct3: (funct3 & 0b111) << 12,
+            funct7: (funct7 & 0b1111111) << 25,
+            funct12: 0,
+            rd: 0,
+            rs1: 0,
+            inst_type
+        }
+    }
+
+    // System instructions (ecall, ebreak) are told apart by funct12 alone.
+    pub fn system(funct12 : u32) -> Self {
+        let mut inst = Self::new(InstType::I, 0x73, 0, 0);
+        inst.funct12 = (funct12 & 0xFFF) << 20;
+        return inst;
+    }
+}
+
 pub struct ExtractedData<T>
 where 
     T : std::clone::Clone
@@ -31,6 +59,23 @@ where
     key: String //
 }
 
+impl<T> ExtractedData<T>
+where 
+    T : std::clone::Clone
+{
+    pub fn new(key : &str, data : T) -> Self {
+        Self { data, key: key.to_string() }
+    }
+
+    pub fn key(&self) -> &str {
+        return &self.key;
+    }
+
+    pub fn data(&self) -> &T {
+        return &self.data;
+    }
+}
+
 #[derive(Clone)]
 pub struct HashNode<T> 
 where 
@@ -38,6 +83,7 @@ where
 {
     data : T, // Data
     key : u32, 
+    name : String, // Original key, used to tell apart keys with the same hash
     next_node : Option<Box<HashNode<T>>>
 }
 
@@ -56,23 +102,24 @@ where
     pub fn new(size : u32) -> Self {
         Self { 
             hash_vect: vec![None; size as usize],
-            size: size, 
+            size, 
         }
     }
 
     // Hashes a string
+    // Keys are identifiers: alphanumerics plus '_', '.' and '$'.
     pub fn hash_str(&self, key: &str) -> Result<u32, io::Error> {
         let mut n: u32 = 1;
         let mut hash_key: u32 = 0;
         
         for chr in key.chars() {
-            if !chr.is_alphanumeric() {
+            if !(chr.is_alphanumeric() || chr == '_' || chr == '.' || chr == '$') {
                 return Err(io::Error::new(io::ErrorKind::InvalidInput,
-                    "Hash Function: Input was not alphanumeric"));
+                    "Hash Function: Input was not an identifier"));
             }
 
-            hash_key +=  (chr.to_ascii_uppercase() as u32) * n;
-            n *= 2;
+            hash_key = hash_key.wrapping_add((chr.to_ascii_uppercase() as u32).wrapping_mul(n));
+            n = n.wrapping_mul(2);
         }
 
         return Ok(hash_key);
@@ -81,10 +128,7 @@ where
 
     // Add copy-cat handling.
     pub fn insert(&mut self, dat : &T, key : &str) -> bool{
-        if key.len() > 10 {
-            return false;
-        }
-        let hash_key_res = HashMap::hash_str(&self,key);
+        let hash_key_res = HashMap::hash_str(self, key);
         if hash_key_res.is_err() {
             return false;
         }
@@ -97,6 +141,7 @@ where
             HashNode {
                 data: dat.clone(),
                 key: hash_key,
+                name: key.to_string(),
                 next_node: None
             }
         );
@@ -114,22 +159,16 @@ where
     }
 
     pub fn get(&self, key : &str) -> Option<&T>{
-        
-        if key.len() > 10 {
-            return None
-        }
-
-        let hash = HashMap::hash_str(&self, key)
-            .expect("Hash function failed: Invalid Input.");
+        let hash = match HashMap::hash_str(self, key) {
+            Ok(hash) => hash,
+            Err(_) => return None
+        };
         let index = (hash % self.size) as usize;
 
         let mut curr = &self.hash_vect[index];
-        if curr.is_none() {
-            panic!("Invalid Key!"); // Panics to be removed in the future
-        }
-
         while let Some(node) = curr {
-            if node.key == hash {
+            // Hashes are case-insensitive, so the names are compared the same way.
+            if node.key == hash && node.name.eq_ignore_ascii_case(key) {
                 return Some(&node.data);
             }
             curr = &node.next_node;
@@ -140,22 +179,39 @@ where
 }
 
 
+// Byte address, wide enough for both 32 and 64-bit address spaces
+pub type Address = u64;
+
 // Fully Parsed Instruction, containing the machine code and the instruction address
 #[derive(Debug ,serde::Deserialize)]
 pub struct ParsedNode {
     pub instruction: u32,
-    pub address:u16,
+    pub address: Address,
+    #[serde(default = "ParsedNode::default_size")]
+    pub size: u8, // Length in bytes, 4 for regular and 2 for compressed instructions
+}
+
+impl ParsedNode {
+    fn default_size() -> u8 {
+        4
+    }
+
+    // Bytes of the node in memory order (little-endian).
+    pub fn bytes(&self) -> Vec<u8> {
+        return self.instruction.to_le_bytes()[..self.size as usize].to_vec();
+    }
 }
 
 impl cmp::PartialEq for ParsedNode {
     fn eq(&self, other: &Self) -> bool {
-        return (self.address == other.address) && (self.instruction == other.instruction);
+        return (self.address == other.address) && (self.instruction == other.instruction)
+            && (self.size == other.size);
     }
 }
 
 impl std::clone::Clone for ParsedNode{
     fn clone(&self) -> Self {
-        ParsedNode { instruction: (self.instruction), address: (self.address) }
+        ParsedNode { instruction: (self.instruction), address: (self.address), size: (self.size) }
     }
 }
 
@@ -163,6 +219,8 @@ impl std::clone::Clone for ParsedNode{
 pub struct DataInterface {
     lines : LinkedList<String>,
     parsed : LinkedList<ParsedNode>,
+    #[serde(default)]
+    files : Vec<(String, usize)>, // Source file names and the index of their first line
 }
 
 impl DataInterface {
@@ -170,7 +228,34 @@ impl DataInterface {
         Self{
             lines: LinkedList::new(),
             parsed: LinkedList::new(),
+            files: Vec::new(),
+        }
+    }
+
+    // Marks the lines added from now on as coming from the named file.
+    pub fn add_file(&mut self, name : &str) {
+        self.files.push((name.to_string(), self.lines.len()));
+    }
+
+    // Finds the file a stored line came from and its 1-based line number in that file.
+    pub fn locate(&self, index : usize) -> (String, usize) {
+        return match self.files.iter().rev().find(|(_, first)| *first <= index) {
+            Some((name, first)) => (name.clone(), index - first + 1),
+            None => ("<input>".to_string(), index + 1)
+        };
+    }
+
+    // Source line a location points at, the inverse of locate.
+    pub fn source_line(&self, loc : &Location) -> Option<&str> {
+        if loc.line == 0 {
+            return None;
         }
+        let first = match self.files.iter().rev().find(|(name, _)| *name == loc.file) {
+            Some((_, first)) => *first,
+            None if self.files.is_empty() => 0,
+            None => return None
+        };
+        return self.line(first + loc.line - 1);
     }
 
     pub fn add_line(&mut self, line : &str) {
@@ -189,6 +274,15 @@ impl DataInterface {
         return self.parsed.pop_front();
     }
 
+    // Reads a stored line without removing it. Index is 0-based.
+    pub fn line(&self, index : usize) -> Option<&str> {
+        return self.lines.iter().nth(index).map(|line| line.as_str());
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = &String> {
+        return self.lines.iter();
+    }
+
     pub fn lines_len(&self) -> usize {
         self.lines.len()
     }
@@ -198,34 +292,58 @@ impl DataInterface {
     }
 
 }
-    
-pub fn str_to_int(src : &str) -> Result<u32, std::num::ParseIntError> {
-    let mut radix = 10;
-    let slc: &str;
-    if src.starts_with("0x") {
-        radix = 16;
-        slc = src.trim_start_matches("0x");
-
-    } else if src.starts_with("0b"){    
-        radix = 2;
-        slc = src.trim_start_matches("0b");
-    
-    } else if src.starts_with("0h") {    
-        radix = 16;
-        slc = src.trim_start_matches("0h");
 
-    } else {
-        slc = src;
+impl Default for DataInterface {
+    fn default() -> Self {
+        Self::new()
     }
+}
+    
+// Splits off a radix prefix: 0x and 0h for hex, 0b for binary, decimal otherwise.
+fn split_radix(src : &str) -> (u32, &str) {
+    if let Some(rest) = src.strip_prefix("0x") {
+        return (16, rest);
+    } else if let Some(rest) = src.strip_prefix("0b") {
+        return (2, rest);
+    } else if let Some(rest) = src.strip_prefix("0h") {
+        return (16, rest);
+    }
+    return (10, src);
+}
 
+pub fn str_to_int(src : &str) -> Result<u32, std::num::ParseIntError> {
+    let (radix, slc) = split_radix(src);
     return u32::from_str_radix(slc, radix);
 }
 
+// Parses an address, using the same prefixes as str_to_int but up to 64 bits.
+pub fn str_to_address(src : &str) -> Result<Address, std::num::ParseIntError> {
+    let (radix, slc) = split_radix(src);
+    return Address::from_str_radix(slc, radix);
+}
+
+// Parses an integer with an optional leading - or +, using the same prefixes as str_to_int.
+// Positive values up to 64 bits are taken as their two's-complement pattern, so 0xFFFFFFFFFFFFFFFF is -1.
+pub fn str_to_signed(src : &str) -> Result<i64, std::num::ParseIntError> {
+    let (negative, unsigned) = match src.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, src.strip_prefix('+').unwrap_or(src))
+    };
+    let (radix, slc) = split_radix(unsigned);
+
+    // A second sign is not allowed, from_str_radix would accept a +
+    let slc = if slc.starts_with(['+', '-']) { "" } else { slc };
+    if negative {
+        return i64::from_str_radix(&format!("-{slc}"), radix);
+    }
+    return u64::from_str_radix(slc, radix).map(|val| val as i64);
+}
+
 #[cfg(test)]
+#[allow(clippy::needless_late_init, clippy::expect_fun_call, clippy::box_default)]
 mod tests {
     use super::*;
     use std::fs;
-    use std::num::ParseIntError;
     use std::path::PathBuf;
     
     #[derive(serde::Deserialize)]
@@ -266,7 +384,7 @@ mod tests {
             let mut di: Box<DataInterface> = Box::new(DataInterface::new());
             let test_num = unit.test_num;
             for st in unit.input.iter() {
-                di.add_line(&st);
+                di.add_line(st);
             }
             assert_eq!(di.lines, unit.check_value,
                 "Test # `{test_num}` failed.");
@@ -310,7 +428,7 @@ mod tests {
         for t in test_vec {
             let test_num = t.test_num;
             let out = test_hm.hash_str(&t.input)
-                .expect("Test #`{test_num}` Failed :(");
+                .unwrap_or_else(|_| panic!("Test #`{test_num}` Failed :("));
             
             assert_eq!(out, t.check_value);
         }
@@ -318,6 +436,46 @@ mod tests {
         return;
     }
 
+    #[test]
+    fn test_hash_map_get() {
+        let mut test_hm = HashMap::<u32>::new(4);
+
+        // x11 and x30 hash to the same value
+        assert!(test_hm.insert(&11, "x11"));
+        assert!(test_hm.insert(&30, "x30"));
+        assert!(test_hm.insert(&1, "a_much_longer_label.name"));
+
+        assert_eq!(test_hm.get("x11"), Some(&11));
+        assert_eq!(test_hm.get("X30"), Some(&30));
+        assert_eq!(test_hm.get("A_MUCH_LONGER_LABEL.NAME"), Some(&1));
+        assert_eq!(test_hm.get("missing"), None);
+        assert_eq!(test_hm.get("not-an-identifier"), None);
+        assert!(!test_hm.insert(&2, "label:"));
+    }
+
+    #[test]
+    fn test_locate() {
+        let mut di = DataInterface::new();
+        di.add_line("before any file");
+        di.add_file("a.s");
+        di.add_line("a1");
+        di.add_line("a2");
+        di.add_file("b.s");
+        di.add_line("b1");
+
+        assert_eq!(di.locate(0), ("<input>".to_string(), 1));
+        assert_eq!(di.locate(2), ("a.s".to_string(), 2));
+        assert_eq!(di.locate(3), ("b.s".to_string(), 1));
+        assert_eq!(di.line(3), Some("b1"));
+
+        let loc = |file : &str, line : usize| Location { file: file.to_string(), line, column: 1 };
+        assert_eq!(di.source_line(&loc("a.s", 2)), Some("a2"));
+        assert_eq!(di.source_line(&loc("b.s", 1)), Some("b1"));
+        assert_eq!(di.source_line(&loc("b.s", 2)), None);
+        assert_eq!(di.source_line(&loc("c.s", 1)), None);
+        assert_eq!(di.source_line(&loc("a.s", 0)), None);
+    }
+
     #[test] 
     fn test_str_to_int() {
         let test_vec : Vec<Test<String, u32>>;
@@ -326,9 +484,29 @@ mod tests {
         for t in test_vec {
             let test_num = t.test_num;
             let out = str_to_int(&t.input)
-                .expect("Test #`{test_num}` Failed :(");
+                .unwrap_or_else(|_| panic!("Test #`{test_num}` Failed :("));
             
             assert_eq!(out, t.check_value);
         }
     }
+
+    #[test]
+    fn test_str_to_address() {
+        assert_eq!(str_to_address("0x80000000"), Ok(0x8000_0000));
+        assert_eq!(str_to_address("0x100000000"), Ok(0x1_0000_0000));
+        assert_eq!(str_to_address("0xFFFFFFFFFFFFFFFF"), Ok(u64::MAX));
+        assert_eq!(str_to_address("4096"), Ok(4096));
+        assert!(str_to_address("-4").is_err());
+        assert!(str_to_address("0x10000000000000000").is_err());
+    }
+
+    #[test]
+    fn test_str_to_signed() {
+        let test_vec : Vec<Test<String, Option<i64>>> = load_tests("test_str_to_signed.json");
+
+        for t in test_vec {
+            let test_num = t.test_num;
+            assert_eq!(str_to_signed(&t.input).ok(), t.check_value, "Test #`{test_num}` Failed :(");
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,300 @@
+use std::{collections::BTreeMap, fmt, io};
+use data_structures::*;
+
+// Intel HEX record types
+pub const REC_DATA: u8 = 0x00;
+pub const REC_EOF: u8 = 0x01;
+pub const REC_EXT_SEGMENT: u8 = 0x02;
+pub const REC_START_SEGMENT: u8 = 0x03;
+pub const REC_EXT_LINEAR: u8 = 0x04;
+pub const REC_START_LINEAR: u8 = 0x05;
+
+// Order the bytes of each parsed node are written in.
+// RISC-V memory is little-endian; Big matches how words are shown in listings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteOrder { Little, Big }
+
+#[derive(Clone, Debug)]
+pub struct HexOptions {
+    pub record_len: usize, // Maximum data bytes per record, usually 16 or 32
+    pub start_address: Option<u32>, // Entry point, defaults to the first parsed address
+    pub byte_order: ByteOrder
+}
+
+impl Default for HexOptions {
+    fn default() -> Self {
+        Self {
+            record_len: 16,
+            start_address: None,
+            byte_order: ByteOrder::Little
+        }
+    }
+}
+
+// Forms one record, including the leading colon, checksum and newline.
+pub fn form_record(rec_type : u8, address : u16, data : &[u8]) -> String {
+    let mut line: String = format!(":{:02X}{:04X}{:02X}", data.len(), address, rec_type);
+    let mut checksum : u8 = (data.len() as u8)
+        .wrapping_add((address >> 8) as u8)
+        .wrapping_add(address as u8)
+        .wrapping_add(rec_type);
+
+    for byte in data.iter() {
+        line.push_str(&format!("{:02X}", byte));
+        checksum = checksum.wrapping_add(*byte);
+    }
+
+    line.push_str(&format!("{:02X}", checksum.wrapping_neg()));
+    line.push('\n');
+    return line;
+}
+
+// Bytes of a parsed node in output order.
+pub fn node_bytes(node : &ParsedNode, byte_order : ByteOrder) -> Vec<u8> {
+    let mut bytes = node.bytes();
+    if byte_order == ByteOrder::Big {
+        bytes.reverse();
+    }
+    return bytes;
+}
+
+// Accumulates bytes into data records, splitting on gaps, full records and 64 KiB boundaries.
+struct RecordBuilder {
+    out: String,
+    record_len: usize,
+    upper: u32, // Upper 16 bits currently selected by an extended linear address record
+    base: u32, // Address of the first byte in data
+    data: Vec<u8>
+}
+
+impl RecordBuilder {
+    fn flush(&mut self) {
+        if self.data.is_empty() {
+            return;
+        }
+        self.out.push_str(&form_record(REC_DATA, self.base as u16, &self.data));
+        self.data.clear();
+    }
+
+    fn push(&mut self, address : u32, byte : u8) {
+        let next = self.base.wrapping_add(self.data.len() as u32);
+        if address != next || self.data.len() >= self.record_len || (address >> 16) != self.upper {
+            self.flush();
+        }
+
+        if (address >> 16) != self.upper {
+            self.upper = address >> 16;
+            let upper = self.upper as u16;
+            self.out.push_str(&form_record(REC_EXT_LINEAR, 0, &upper.to_be_bytes()));
+        }
+
+        if self.data.is_empty() {
+            self.base = address;
+        }
+        self.data.push(byte);
+    }
+}
+
+// Drains the parsed nodes into a complete Intel HEX image.
+// Contiguous nodes are merged into records of up to record_len bytes.
+pub fn form_hex(di : &mut DataInterface, opts : &HexOptions) -> io::Result<String> {
+    if opts.record_len == 0 || opts.record_len > 0xFF {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "Intel HEX: record length must be between 1 and 255 bytes"));
+    }
+
+    let mut builder = RecordBuilder {
+        out: String::new(),
+        record_len: opts.record_len,
+        upper: 0,
+        base: 0,
+        data: Vec::new()
+    };
+    let mut start = opts.start_address;
+
+    while let Some(node) = di.pop_parsed() {
+        // Intel HEX can only address 32 bits
+        let last = node.address.saturating_add(node.size.max(1) as u64 - 1);
+        if last > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Intel HEX: address {:#X} is above the 32-bit address space", node.address)));
+        }
+
+        let address = node.address as u32;
+        if start.is_none() {
+            start = Some(address);
+        }
+
+        for (i, byte) in node_bytes(&node, opts.byte_order).iter().enumerate() {
+            builder.push(address.wrapping_add(i as u32), *byte);
+        }
+    }
+    builder.flush();
+
+    if let Some(start) = start {
+        builder.out.push_str(&form_record(REC_START_LINEAR, 0, &start.to_be_bytes()));
+    }
+    builder.out.push_str(&form_record(REC_EOF, 0, &[]));
+    return Ok(builder.out);
+}
+
+// A single decoded record.
+#[derive(Debug, PartialEq)]
+pub struct HexRecord {
+    pub rec_type: u8,
+    pub address: u16,
+    pub data: Vec<u8>
+}
+
+// Error while reading an Intel HEX file. Line is 1-based, or 0 when not tied to a line.
+#[derive(Debug, PartialEq)]
+pub struct HexError {
+    pub line: usize,
+    pub message: String
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            return write!(f, "{}", self.message);
+        }
+        return write!(f, "line {}: {}", self.line, self.message);
+    }
+}
+
+// Decodes one record, validating its length and checksum.
+pub fn parse_record(line : &str) -> Result<HexRecord, String> {
+    let body = match line.trim_end().strip_prefix(':') {
+        Some(body) => body,
+        None => return Err("record does not start with `:`".to_string())
+    };
+
+    if body.len() % 2 != 0 || !body.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("record is not made of hex byte pairs".to_string());
+    }
+
+    let bytes: Vec<u8> = (0..body.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&body[i..i + 2], 16).unwrap())
+        .collect();
+    if bytes.len() < 5 {
+        return Err("record is too short".to_string());
+    }
+
+    let data_len = bytes[0] as usize;
+    if bytes.len() != data_len + 5 {
+        return Err(format!("byte count is {} but the record holds {} data bytes",
+            data_len, bytes.len() - 5));
+    }
+
+    let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    if checksum != 0 {
+        let expected = bytes[..bytes.len() - 1].iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+            .wrapping_neg();
+        return Err(format!("bad checksum {:02X}, expected {:02X}", bytes[bytes.len() - 1], expected));
+    }
+
+    return Ok(HexRecord {
+        rec_type: bytes[3],
+        address: u16::from_be_bytes([bytes[1], bytes[2]]),
+        data: bytes[4..bytes.len() - 1].to_vec()
+    });
+}
+
+// Reads a complete Intel HEX image into parsed nodes, one per 4 bytes of each contiguous run.
+// A run that does not fill its last word ends with a shorter node.
+// Returns the start address, if the image has one.
+pub fn read_hex(content : &str, di : &mut DataInterface, byte_order : ByteOrder) -> Result<Option<u32>, HexError> {
+    let mut memory: BTreeMap<u32, u8> = BTreeMap::new();
+    let mut base: u32 = 0; // Set by extended segment/linear address records
+    let mut start: Option<u32> = None;
+    let mut eof_line: Option<usize> = None;
+
+    for (i, line) in content.lines().enumerate() {
+        let line_num = i + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(eof) = eof_line {
+            return Err(HexError { line: line_num,
+                message: format!("record after the end-of-file record on line {eof}") });
+        }
+
+        let record = parse_record(line)
+            .map_err(|message| HexError { line: line_num, message })?;
+        let expect_len = |len : usize| -> Result<(), HexError> {
+            if record.data.len() != len {
+                return Err(HexError { line: line_num,
+                    message: format!("record type {:02X} needs {} data bytes", record.rec_type, len) });
+            }
+            Ok(())
+        };
+
+        match record.rec_type {
+            REC_DATA => {
+                for (offset, byte) in record.data.iter().enumerate() {
+                    let address = base.wrapping_add(record.address as u32 + offset as u32);
+                    if memory.insert(address, *byte).is_some() {
+                        return Err(HexError { line: line_num,
+                            message: format!("address {:08X} is written twice", address) });
+                    }
+                }
+            },
+            REC_EOF => {
+                expect_len(0)?;
+                eof_line = Some(line_num);
+            },
+            REC_EXT_SEGMENT => {
+                expect_len(2)?;
+                base = (u16::from_be_bytes([record.data[0], record.data[1]]) as u32) << 4;
+            },
+            REC_START_SEGMENT => {
+                expect_len(4)?;
+                let cs = u16::from_be_bytes([record.data[0], record.data[1]]) as u32;
+                let ip = u16::from_be_bytes([record.data[2], record.data[3]]) as u32;
+                start = Some((cs << 4) + ip);
+            },
+            REC_EXT_LINEAR => {
+                expect_len(2)?;
+                base = (u16::from_be_bytes([record.data[0], record.data[1]]) as u32) << 16;
+            },
+            REC_START_LINEAR => {
+                expect_len(4)?;
+                start = Some(u32::from_be_bytes([record.data[0], record.data[1], record.data[2], record.data[3]]));
+            },
+            other => {
+                return Err(HexError { line: line_num,
+                    message: format!("unknown record type {:02X}", other) });
+            }
+        }
+    }
+
+    if eof_line.is_none() {
+        return Err(HexError { line: 0, message: "missing end-of-file record".to_string() });
+    }
+
+    // Groups the bytes into words, restarting at every gap.
+    let mut entries = memory.into_iter().peekable();
+    while let Some((address, first)) = entries.next() {
+        let mut word = vec![first];
+        while word.len() < 4 {
+            match entries.peek() {
+                Some((next, _)) if *next as u64 == address as u64 + word.len() as u64 => {
+                    word.push(entries.next().unwrap().1);
+                },
+                _ => break
+            }
+        }
+
+        if byte_order == ByteOrder::Big {
+            word.reverse();
+        }
+        let instruction = word.iter()
+            .rev()
+            .fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+        di.add_parsed(&ParsedNode { instruction, address: address as Address, size: word.len() as u8 });
+    }
+
+    return Ok(start);
+}
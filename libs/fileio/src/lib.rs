@@ -1,60 +1,97 @@
-use std::{fmt, fs, path::PathBuf};
+#![allow(clippy::needless_return)]
+
+pub mod hex;
+
+use std::{fs, io, path::PathBuf};
 use data_structures::*;
-use serde::*;
+pub use hex::{ByteOrder, HexError, HexOptions};
+
+// Largest binary image, from the lowest to the highest address. Sections placed far apart would
+// otherwise be joined by gigabytes of fill.
+pub const MAX_BIN_SIZE : u64 = 64 * 1024 * 1024;
+
 #[derive(serde::Deserialize, Debug)]
 
 pub struct FileIO {
 }
 
 impl FileIO {
-    pub fn open_code(&mut self, path: &PathBuf, di : &mut DataInterface){
-        let content: String = fs::read_to_string(path)
-            .expect("Failed to read code!");
+    // Reads a source file into the data interface, one line at a time.
+    pub fn open_code(&mut self, path: &PathBuf, di : &mut DataInterface) -> Result<(), AssemblerError> {
+        let content: String = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => return Err(AssemblerError::Io {
+                loc: Location { file: path.display().to_string(), line: 0, column: 0 },
+                token: path.display().to_string(),
+                message: err.to_string()
+            })
+        };
         let strvec = content.split('\n');
         
+        di.add_file(&path.display().to_string());
         for line in strvec.into_iter() {
             di.add_line(line);
         }
-        return;
+        return Ok(());
     }
 
-    // Takes each parsed instruction and forms a line of intel hex with it
-    fn form_line(node : &ParsedNode) -> Option<String> {
-        let mut line: String = "04".to_owned();
-        // Formats address to only 4 bytes
-        line.push_str(&format!("{:04X}", node.address)[..]);
-        line.push_str("00");
-        // Formats instruction
-        line.push_str(&format!("{:08X}", node.instruction)[..]);
-        
-        // Checksum calculations 
-        let mut checksum : u8 = 0;
-
-        // Converts the string into a vector of charater pairs, as checksum is done by byte.
-        let pair_vect : Vec<String> = line.as_bytes()
-            .chunks(2)
-            .map(|chunk| String::from_utf8_lossy(chunk).to_string())
-            .collect();
-
-        // Iterates through pair vector in order to calculate checksum.
-        for pair in pair_vect.iter() {
-            let fm_hex =u8::from_str_radix(pair, 16)
-                .expect("Invalid String");
-            checksum = checksum.wrapping_add(fm_hex);
+    // Drains the parsed instructions into a complete intel hex file.
+    pub fn write_hex(&mut self, path: &PathBuf, di : &mut DataInterface, opts : &HexOptions) -> io::Result<()> {
+        let content = hex::form_hex(di, opts)?;
+        return fs::write(path, content);
+    }
+
+    // Loads a previously written intel hex file into the parsed list.
+    // Returns the image's start address, if it has one.
+    pub fn read_hex(&mut self, path: &PathBuf, di : &mut DataInterface, byte_order : ByteOrder) -> Result<Option<u32>, HexError> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| HexError { line: 0, message: format!("{}: {}", path.display(), err) })?;
+        return hex::read_hex(&content, di, byte_order);
+    }
+
+    // Drains the parsed instructions into a flat binary image, little-endian.
+    // The image starts at the lowest address, any gaps are filled with the fill byte.
+    // Images spanning more than MAX_BIN_SIZE bytes are rejected.
+    pub fn form_bin(di : &mut DataInterface, fill : u8) -> io::Result<Vec<u8>> {
+        let mut nodes: Vec<ParsedNode> = Vec::new();
+        while let Some(node) = di.pop_parsed() {
+            nodes.push(node);
+        }
+
+        let mut content: Vec<u8> = Vec::new();
+        let base = nodes.iter().map(|node| node.address).min().unwrap_or(0);
+        let end = nodes.iter().map(|node| node.address.saturating_add(node.size as Address)).max().unwrap_or(0);
+        if end - base > MAX_BIN_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("binary: the image spans {base:#X} to {end:#X}, more than {MAX_BIN_SIZE} bytes")));
+        }
+        for node in nodes.iter() {
+            let offset = (node.address - base) as usize;
+            let bytes = node.bytes();
+            if content.len() < offset + bytes.len() {
+                content.resize(offset + bytes.len(), fill);
+            }
+            content[offset..offset + bytes.len()].copy_from_slice(&bytes);
         }
-        checksum = !checksum + 1;
+        return Ok(content);
+    }
 
-        // Putting together the final return line.
-        line.push_str(&format!("{:02X}", checksum));
-        let mut ret_string: String = ":".to_owned();
-        ret_string.push_str(&line[..]);
-        ret_string.push('\n');
+    pub fn write_bin(&mut self, path: &PathBuf, di : &mut DataInterface, fill : u8) -> io::Result<()> {
+        return fs::write(path, Self::form_bin(di, fill)?);
+    }
 
-        return Some(ret_string);
+    // Takes each parsed instruction and forms a line of intel hex with it
+    // The word is written most significant byte first, as it reads in a listing.
+    // A record only holds 16 address bits, None above that. write_hex adds the records those need.
+    pub fn form_line(node : &ParsedNode) -> Option<String> {
+        let address = u16::try_from(node.address).ok()?;
+        let data = hex::node_bytes(node, ByteOrder::Big);
+        return Some(hex::form_record(hex::REC_DATA, address, &data));
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::expect_fun_call)]
 mod tests {
     use super::*;
 
@@ -88,6 +125,129 @@ mod tests {
             assert_eq!(out, curr_test.check_value,
                 "Test # `{test_num}` failed.");
         }
+
+        // Addresses past 16 bits are not cut down
+        assert_eq!(FileIO::form_line(&ParsedNode { instruction: 0x13, address: 0x1_0000, size: 4 }), None);
+    }
+
+    #[test]
+    fn test_form_hex() {
+        let tests: Vec<Test<Vec<ParsedNode>, String>> = load_tests("test_form_hex.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let mut di = DataInterface::new();
+            for node in curr_test.input.iter() {
+                di.add_parsed(node);
+            }
+            let out = hex::form_hex(&mut di, &HexOptions::default()).expect("form_hex failed!");
+            assert_eq!(out, curr_test.check_value,
+                "Test # `{test_num}` failed.");
+            assert_eq!(di.parsed_len(), 0, "Test # `{test_num}` did not drain the nodes.");
+        }
+    }
+
+    #[test]
+    fn test_form_hex_options() {
+        let mut di = DataInterface::new();
+        for address in (0..20).step_by(4) {
+            di.add_parsed(&ParsedNode { instruction: 0x13, address, size: 4 });
+        }
+        let opts = HexOptions { record_len: 32, start_address: Some(0x80), byte_order: ByteOrder::Big };
+        let out = hex::form_hex(&mut di, &opts).expect("form_hex failed!");
+        assert_eq!(out, ":1400000000000013000000130000001300000013000000138D\n\
+            :040000050000008077\n:00000001FF\n");
+
+        let bad_opts = HexOptions { record_len: 0, ..HexOptions::default() };
+        assert!(hex::form_hex(&mut di, &bad_opts).is_err());
+
+        // Intel HEX stops at 32-bit addresses
+        di.add_parsed(&ParsedNode { instruction: 0x13, address: 0xFFFF_FFFE, size: 4 });
+        assert!(hex::form_hex(&mut di, &HexOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_read_form_line() {
+        let tests: Vec<Test<ParsedNode, String>> = load_tests("test_form_line.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let mut di = DataInterface::new();
+            let content = FileIO::form_line(&curr_test.input).expect("form_line failed!") + ":00000001FF\n";
+            let start = hex::read_hex(&content, &mut di, ByteOrder::Big)
+                .unwrap_or_else(|err| panic!("Test # `{test_num}` failed: {err}"));
+            assert_eq!(start, None, "Test # `{test_num}` failed.");
+            assert_eq!(di.pop_parsed(), Some(curr_test.input.clone()),
+                "Test # `{test_num}` failed.");
+            assert_eq!(di.parsed_len(), 0, "Test # `{test_num}` failed.");
+        }
+    }
+
+    #[test]
+    fn test_read_form_hex() {
+        let tests: Vec<Test<Vec<ParsedNode>, String>> = load_tests("test_form_hex.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let mut di = DataInterface::new();
+            let start = hex::read_hex(&curr_test.check_value, &mut di, ByteOrder::Little)
+                .unwrap_or_else(|err| panic!("Test # `{test_num}` failed: {err}"));
+            assert_eq!(start, curr_test.input.first().map(|node| node.address as u32),
+                "Test # `{test_num}` failed.");
+
+            let mut out: Vec<ParsedNode> = Vec::new();
+            while let Some(node) = di.pop_parsed() {
+                out.push(node);
+            }
+            assert_eq!(out, curr_test.input, "Test # `{test_num}` failed.");
+        }
+    }
+
+    #[test]
+    fn test_read_hex_errors() {
+        let tests: Vec<Test<String, usize>> = load_tests("test_read_hex_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let mut di = DataInterface::new();
+            let err = hex::read_hex(&curr_test.input, &mut di, ByteOrder::Little)
+                .expect_err(&format!("Test # `{test_num}` accepted a malformed file."));
+            assert_eq!(err.line, curr_test.check_value,
+                "Test # `{test_num}` failed: {err}");
+        }
+    }
+
+    #[test]
+    fn test_form_bin() {
+        let mut di = DataInterface::new();
+        di.add_parsed(&ParsedNode { instruction: 0x00100093, address: 0x100, size: 4 });
+        di.add_parsed(&ParsedNode { instruction: 0xAB, address: 0x10A, size: 1 });
+        di.add_parsed(&ParsedNode { instruction: 0x4515, address: 0x104, size: 2 });
+
+        // Gaps between nodes are padded, the image starts at the lowest address
+        let out = FileIO::form_bin(&mut di, 0xFF).expect("Image was rejected");
+        assert_eq!(out, vec![0x93, 0x00, 0x10, 0x00, 0x15, 0x45, 0xFF, 0xFF, 0xFF, 0xFF, 0xAB]);
+        assert_eq!(di.parsed_len(), 0);
+        assert!(FileIO::form_bin(&mut di, 0).expect("Empty image was rejected").is_empty());
+
+        // Nodes far apart would need a huge image
+        di.add_parsed(&ParsedNode { instruction: 0x13, address: 0, size: 4 });
+        di.add_parsed(&ParsedNode { instruction: 0x13, address: 0x100000000000000, size: 4 });
+        assert!(FileIO::form_bin(&mut di, 0).is_err());
+
+    }
+
+    #[test]
+    fn test_open_code() {
+        let mut fio = FileIO {};
+        let mut di = DataInterface::new();
+        let path = PathBuf::from(std::env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("missing.s");
+
+        let err = fio.open_code(&path, &mut di).expect_err("Missing file opened");
+        assert!(matches!(err, AssemblerError::Io { .. }));
+        assert_eq!(err.token(), path.display().to_string());
+        assert_eq!(di.lines_len(), 0);
     }
-    
 }
@@ -0,0 +1,201 @@
+use std::fmt;
+use data_structures::*;
+use serde::Serialize;
+
+// ANSI escapes used when rendering to a terminal
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity { Error, Warning }
+
+impl Severity {
+    fn color(&self) -> &'static str {
+        return match self {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW
+        };
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning")
+        };
+    }
+}
+
+// Layout of a diagnostic in JSON output. Columns are 1-based, the end is exclusive.
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    severity: Severity,
+    code: &'a str,
+    message: &'a str,
+    file: &'a str,
+    line: usize,
+    column_start: usize,
+    column_end: usize,
+    suggestion: Option<&'a str>,
+}
+
+// A single error or warning found while translating.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str, // Stable identifier, e.g. "bad-register"
+    pub loc: Location,
+    pub token: String, // Token the diagnostic points at
+    pub message: String,
+    pub help: Option<String>, // Hint on how to fix it
+}
+
+impl Diagnostic {
+    pub fn warning(code : &'static str, loc : Location, token : &str, message : String) -> Self {
+        Self { severity: Severity::Warning, code, loc, token: token.to_string(), message, help: None }
+    }
+
+    pub fn is_error(&self) -> bool {
+        return self.severity == Severity::Error;
+    }
+
+    // Renders the diagnostic like a compiler would, underlining the token in its source line:
+    //
+    // error: `x32` is not a register
+    //  --> prog.s:2:10
+    //   |
+    // 2 |     addi x2, x32, 1
+    //   |              ^^^
+    //   = help: x32 is not a register; valid range is x0–x31
+    pub fn render(&self, source : Option<&str>, color : bool) -> String {
+        let paint = |style : &'static str, text : &str| -> String {
+            if color {
+                return format!("{style}{text}{RESET}");
+            }
+            return text.to_string();
+        };
+
+        let mut out = format!("{}{}\n",
+            paint(self.severity.color(), &self.severity.to_string()),
+            paint(BOLD, &format!(": {}", self.message)));
+
+        let line_num = self.loc.line.to_string();
+        let pad = " ".repeat(line_num.len());
+        out.push_str(&format!("{pad}{} {}\n", paint(BLUE, "-->"), self.loc));
+
+        if let Some(source) = source {
+            let source = source.trim_end();
+            let gutter = paint(BLUE, "|");
+
+            // Keeps tabs so the underline stays aligned with the source
+            let indent: String = source.chars()
+                .take(self.loc.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let underline = "^".repeat(self.token.chars().count().max(1));
+
+            out.push_str(&format!("{pad} {gutter}\n"));
+            out.push_str(&format!("{} {gutter} {source}\n", paint(BLUE, &line_num)));
+            out.push_str(&format!("{pad} {gutter} {indent}{}\n", paint(self.severity.color(), &underline)));
+        }
+
+        if let Some(help) = &self.help {
+            out.push_str(&format!("{pad} {} {help}\n", paint(BOLD, "= help:")));
+        }
+        return out;
+    }
+
+    // The diagnostic as a single line of JSON, for editors and scripts.
+    pub fn to_json(&self) -> String {
+        let json = JsonDiagnostic {
+            severity: self.severity,
+            code: self.code,
+            message: &self.message,
+            file: &self.loc.file,
+            line: self.loc.line,
+            column_start: self.loc.column,
+            column_end: self.loc.column + self.token.chars().count().max(1),
+            suggestion: self.help.as_deref(),
+        };
+        return serde_json::to_string(&json).expect("Diagnostic could not be serialized");
+    }
+}
+
+// Describes a range by the field it fits: N unsigned bits (0..2^N-1), N signed bits
+// (-2^(N-1)..2^(N-1)-1, or up to 2^(N-1)-2 for branch offsets), or either of them (-2^(N-1)..2^N-1)
+// as data directives and li take. Other ranges are given as they are.
+fn range_help(value : i64, min : i64, max : i64) -> String {
+    let (min, max) = (min as i128, max as i128);
+    if min == 0 && ((max + 1) as u128).is_power_of_two() {
+        let bits = ((max + 1) as u128).ilog2();
+        return format!("immediate {value} does not fit in {bits} unsigned bits");
+    }
+    if min < 0 && ((-min) as u128).is_power_of_two() {
+        let bits = ((-min) as u128).ilog2() + 1;
+        if max == -min - 1 || max == -min - 2 {
+            return format!("immediate {value} does not fit in {bits} signed bits");
+        }
+        if max == 2 * -min - 1 {
+            return format!("immediate {value} fits in neither {bits} signed nor {bits} unsigned bits");
+        }
+    }
+    return format!("immediate {value} must be within {min}..={max}");
+}
+
+// Hint shown under the source line, for the errors where one helps.
+fn help(err : &AssemblerError) -> Option<String> {
+    return match err {
+        AssemblerError::BadRegister { token, .. } =>
+            Some(format!("{token} is not a register; valid range is x0–x31")),
+        AssemblerError::ImmediateOutOfRange { value, min, max, .. } => Some(range_help(*value, *min, *max)),
+        AssemblerError::MisalignedTarget { offset, align, .. } =>
+            Some(format!("offset {offset} is not a multiple of {align}")),
+        AssemblerError::DuplicateLabel { token, previous, .. } =>
+            Some(format!("`{token}` was first defined at {previous}, only .set and `=` can change a symbol")),
+        _ => None
+    };
+}
+
+impl From<AssemblerError> for Diagnostic {
+    fn from(err : AssemblerError) -> Self {
+        Self {
+            severity: Severity::Error,
+            code: err.code(),
+            loc: err.location().clone(),
+            token: err.token().to_string(),
+            message: err.message(),
+            help: help(&err)
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "{}: {}: {}", self.severity, self.loc, self.message);
+    }
+}
+
+fn plural(count : usize, noun : &str) -> String {
+    if count == 1 {
+        return format!("1 {noun}");
+    }
+    return format!("{count} {noun}s");
+}
+
+// Counts the diagnostics, e.g. "3 errors, 1 warning". None when there are none.
+pub fn summary(diagnostics : &[Diagnostic]) -> Option<String> {
+    let errors = diagnostics.iter().filter(|diag| diag.is_error()).count();
+    let warnings = diagnostics.len() - errors;
+
+    return match (errors, warnings) {
+        (0, 0) => None,
+        (0, _) => Some(plural(warnings, "warning")),
+        (_, 0) => Some(plural(errors, "error")),
+        (_, _) => Some(format!("{}, {}", plural(errors, "error"), plural(warnings, "warning")))
+    };
+}
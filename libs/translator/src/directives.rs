@@ -0,0 +1,476 @@
+use data_structures::*;
+use crate::{TranslateResult, Translator};
+use crate::sections::SectionFlags;
+use crate::symbols::SymbolKind;
+
+// Directives are META entries in the instruction table, told apart by their opcode.
+pub(crate) const ORG : u32 = 1;
+pub(crate) const BYTE : u32 = 2;
+pub(crate) const HALF : u32 = 3;
+pub(crate) const WORD : u32 = 4;
+pub(crate) const DWORD : u32 = 5;
+pub(crate) const ASCII : u32 = 6;
+pub(crate) const ASCIZ : u32 = 7;
+pub(crate) const P2ALIGN : u32 = 8;
+pub(crate) const BALIGN : u32 = 9;
+pub(crate) const SPACE : u32 = 10;
+pub(crate) const ZERO : u32 = 11;
+pub(crate) const FILL : u32 = 12;
+pub(crate) const EQU : u32 = 13;
+pub(crate) const SET : u32 = 14;
+pub(crate) const SECTION_NAME : u32 = 15; // .text, .data, .rodata and .bss
+pub(crate) const SECTION : u32 = 16;
+
+// Padding of executable code, addi x0, x0, 0 and c.nop
+const NOP : u32 = 0x00000013;
+const C_NOP : u32 = 0x0001;
+
+// Largest alignment, as a power of 2
+const MAX_P2ALIGN : i64 = 31;
+
+// (directive, opcode)
+const DIRECTIVES: [(&str, u32); 24] = [
+    (".org", ORG),
+    (".byte", BYTE),
+    (".half", HALF),
+    (".2byte", HALF),
+    (".word", WORD),
+    (".4byte", WORD),
+    (".dword", DWORD),
+    (".8byte", DWORD),
+    (".ascii", ASCII),
+    (".asciz", ASCIZ),
+    (".string", ASCIZ),
+    // .align is a power of 2 on RISC-V, as on most targets
+    (".align", P2ALIGN),
+    (".p2align", P2ALIGN),
+    (".balign", BALIGN),
+    (".space", SPACE),
+    (".zero", ZERO),
+    (".fill", FILL),
+    (".equ", EQU),
+    (".set", SET),
+    (".text", SECTION_NAME),
+    (".data", SECTION_NAME),
+    (".rodata", SECTION_NAME),
+    (".bss", SECTION_NAME),
+    (".section", SECTION),
+];
+
+// The directive table, part of every translator's instruction table.
+pub fn directives() -> Vec<ExtractedData<Vec<Inst>>> {
+    return DIRECTIVES.iter()
+        .map(|(name, opcode)| ExtractedData::new(name, vec![Inst::new(InstType::META, *opcode, 0, 0)]))
+        .collect();
+}
+
+// Bytes of a quoted string, with C escapes: \n \t \r \a \b \f \v \\ \" \', \xNN in hex
+// and up to three octal digits such as \0.
+fn string_bytes(token : &str) -> Result<Vec<u8>, String> {
+    let body = token.strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a string in double quotes, found `{token}`"))?;
+
+    let mut out: Vec<u8> = Vec::new();
+    let mut chars = body.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            return Err(format!("`{token}` has a quote inside it, write it as \\\""));
+        }
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+            out.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        let Some(escaped) = chars.next() else {
+            return Err(format!("`{token}` ends in the middle of an escape"));
+        };
+        let byte = match escaped {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0C,
+            'v' => 0x0B,
+            '\\' | '"' | '\'' => escaped as u8,
+            'x' => {
+                let mut value: u32 = 0;
+                let mut digits = 0;
+                while digits < 2 && let Some(digit) = chars.peek().and_then(|c| c.to_digit(16)) {
+                    value = value * 16 + digit;
+                    digits += 1;
+                    chars.next();
+                }
+                if digits == 0 {
+                    return Err(format!("`\\x` in `{token}` is not followed by hex digits"));
+                }
+                value as u8
+            },
+            '0'..='7' => {
+                let mut value = escaped.to_digit(8).unwrap_or(0);
+                let mut digits = 1;
+                while digits < 3 && let Some(digit) = chars.peek().and_then(|c| c.to_digit(8)) {
+                    value = value * 8 + digit;
+                    digits += 1;
+                    chars.next();
+                }
+                if value > 0xFF {
+                    return Err(format!("`\\{value:o}` in `{token}` does not fit in a byte"));
+                }
+                value as u8
+            },
+            _ => return Err(format!("`\\{escaped}` in `{token}` is not an escape sequence"))
+        };
+        out.push(byte);
+    }
+    return Ok(out);
+}
+
+impl Translator<'_> {
+    // Checks a directive has between min and max operands.
+    fn check_operand_range(&self, broken_line : &[&str], min : usize, max : usize) -> TranslateResult {
+        let found = broken_line.len() - 1;
+        if found < min || found > max {
+            return Err(AssemblerError::WrongOperandCount {
+                loc: self.loc(broken_line[0]),
+                token: broken_line[0].to_string(),
+                expected: if found < min { min } else { max },
+                found
+            });
+        }
+        Ok(())
+    }
+
+    // .org address[, fill]
+    // Moves the location counter forward. Numbers are offsets from the base of the section, labels are
+    // addresses already. The gap is left out of the image unless a fill byte is given.
+    pub(crate) fn org(&mut self, broken_line : &[&str]) -> TranslateResult {
+        self.check_operand_range(broken_line, 1, 2)?;
+
+        let value = self.eval(broken_line[1])?;
+        let target = match value.is_address() {
+            true => value.val as i128,
+            false => self.sections.current().base as i128 + value.val as i128
+        };
+
+        if target < self.curr_address as i128 {
+            return Err(AssemblerError::Syntax {
+                loc: self.loc(broken_line[1]),
+                token: broken_line[1].to_string(),
+                message: format!("`.org` cannot move backwards, from {:#X} to {target:#X}", self.curr_address)
+            });
+        }
+        // The counter may end up just past the last address, as long as nothing is placed there
+        if target > self.xlen.max_address() as i128 + 1 {
+            return Err(AssemblerError::AddressOverflow {
+                loc: self.loc(broken_line[1]),
+                token: broken_line[1].to_string(),
+                address: target as Address
+            });
+        }
+
+        let target = target as Address;
+        if let Some(fill) = self.fill_operand(broken_line, 2)? {
+            self.fill_bytes(target - self.curr_address, fill, broken_line[0])?;
+        }
+        self.curr_address = target;
+        Ok(())
+    }
+
+    // .byte/.half/.word/.dword value, ...
+    // Places each value at the current address in size bytes, little-endian. A value may be
+    // signed or unsigned, so .byte takes -128 to 255. Labels give their address.
+    pub(crate) fn data(&mut self, broken_line : &[&str], size : u32) -> TranslateResult {
+        self.check_operand_range(broken_line, 1, usize::MAX)?;
+
+        for token in broken_line[1..].iter() {
+            // A bad value still takes its space, so the labels after it stay put
+            let value = match self.data_value(token, size) {
+                Ok(value) => value,
+                Err(err) => {
+                    self.report(err);
+                    0
+                }
+            };
+
+            self.emit_value(value, size, broken_line[0])?;
+        }
+        Ok(())
+    }
+
+    // Places the low size bytes of a value, little-endian.
+    fn emit_value(&mut self, value : i64, size : u32, token : &str) -> TranslateResult {
+        // Words are the most a node holds, a double word takes two
+        let mut rest = value as u64;
+        let mut left = size;
+        while left > 0 {
+            let chunk = left.min(4);
+            let mask = u32::MAX >> (32 - chunk * 8);
+            self.emit(rest as u32 & mask, chunk as u8, token)?;
+            rest >>= 32;
+            left -= chunk;
+        }
+        Ok(())
+    }
+
+    fn data_value(&self, token : &str, size : u32) -> Result<i64, AssemblerError> {
+        let value = self.imm(token)?;
+        if size < 8 {
+            let bits = size * 8;
+            self.check_range(token, value, -(1 << (bits - 1)), (1 << bits) - 1)?;
+        }
+        return Ok(value);
+    }
+
+    // .ascii/.asciz/.string "text", ...
+    // Places the bytes of each string, .asciz and .string end each with a zero byte.
+    pub(crate) fn string(&mut self, broken_line : &[&str], terminate : bool) -> TranslateResult {
+        self.check_operand_range(broken_line, 1, usize::MAX)?;
+
+        // Checked up front, as a string cannot keep its space after an error in it
+        let mut strings: Vec<Vec<u8>> = Vec::new();
+        for token in broken_line[1..].iter() {
+            let mut bytes = string_bytes(token).map_err(|message| AssemblerError::Syntax {
+                loc: self.loc(token),
+                token: token.to_string(),
+                message
+            })?;
+            if terminate {
+                bytes.push(0);
+            }
+            strings.push(bytes);
+        }
+
+        for byte in strings.concat() {
+            self.emit(byte as u32, 1, broken_line[0])?;
+        }
+        Ok(())
+    }
+
+    // A count or size operand, which has to be a number that is not negative.
+    fn count(&self, token : &str) -> Result<u64, AssemblerError> {
+        let value = self.eval(token)?;
+        if value.is_address() || value.val < 0 {
+            return Err(AssemblerError::Syntax {
+                loc: self.loc(token),
+                token: token.to_string(),
+                message: format!("`{token}` must be a number that is not negative")
+            });
+        }
+        return Ok(value.val as u64);
+    }
+
+    // The fill byte at position i of the operands, None when it is left out.
+    fn fill_operand(&self, broken_line : &[&str], i : usize) -> Result<Option<u8>, AssemblerError> {
+        let Some(token) = broken_line.get(i).filter(|token| !token.is_empty()) else {
+            return Ok(None);
+        };
+        let fill = self.imm(token)?;
+        self.check_range(token, fill, -128, 255)?;
+        return Ok(Some(fill as u8));
+    }
+
+    // Moves past size bytes without placing them when nothing is written: in the layout passes
+    // and in sections such as .bss. False when the bytes still have to be emitted.
+    fn skip(&mut self, size : Address, zero : bool, token : &str) -> Result<bool, AssemblerError> {
+        self.check_address(size, token)?;
+        let nobits = self.sections.current().flags.nobits;
+        if nobits && !zero && size > 0 {
+            return Err(self.nobits_err(token, "non-zero data"));
+        }
+        if !self.layout_pass && !nobits && size > 0 {
+            return Ok(false);
+        }
+        self.curr_address += size;
+        return Ok(true);
+    }
+
+    // Places count copies of a byte, a word at a time.
+    fn fill_bytes(&mut self, count : u64, byte : u8, token : &str) -> TranslateResult {
+        if self.skip(count, byte == 0, token)? {
+            return Ok(());
+        }
+        let word = byte as u32 * 0x01010101;
+        let mut left = count;
+        while left > 0 {
+            let chunk = left.min(4) as u8;
+            self.emit(word >> (32 - chunk as u32 * 8), chunk, token)?;
+            left -= chunk as u64;
+        }
+        Ok(())
+    }
+
+    // Pads up to the target address, with the fill byte when there is one. Otherwise code is padded
+    // with nops, with zeros short of an instruction boundary, and data with zeros.
+    fn pad(&mut self, target : Address, fill : Option<u8>, token : &str) -> TranslateResult {
+        let zero = fill.map_or(!self.sections.current().flags.exec, |fill| fill == 0);
+        if self.skip(target - self.curr_address, zero, token)? {
+            return Ok(());
+        }
+        if let Some(fill) = fill {
+            return self.fill_bytes(target - self.curr_address, fill, token);
+        }
+        if !self.sections.current().flags.exec {
+            return self.fill_bytes(target - self.curr_address, 0, token);
+        }
+
+        let step: Address = if self.compressed { 2 } else { 4 };
+        let boundary = self.curr_address.next_multiple_of(step).min(target);
+        self.fill_bytes(boundary - self.curr_address, 0, token)?;
+
+        // c.nop brings the counter to a word boundary, then whole nops follow
+        if self.compressed && self.curr_address % 4 == 2 && target - self.curr_address >= 2 {
+            self.emit(C_NOP, 2, token)?;
+        }
+        while target - self.curr_address >= 4 {
+            self.emit(NOP, 4, token)?;
+        }
+        if self.compressed && target - self.curr_address >= 2 {
+            self.emit(C_NOP, 2, token)?;
+        }
+        return self.fill_bytes(target - self.curr_address, 0, token);
+    }
+
+    // .align/.p2align power[, fill[, max]] and .balign bytes[, fill[, max]]
+    // Pads to the next multiple of the alignment, unless that takes more than max bytes.
+    pub(crate) fn align(&mut self, broken_line : &[&str], power_of_2 : bool) -> TranslateResult {
+        self.check_operand_range(broken_line, 1, 3)?;
+
+        let value = self.count(broken_line[1])?;
+        let align: u64 = if power_of_2 {
+            self.check_range(broken_line[1], value as i64, 0, MAX_P2ALIGN)?;
+            1 << value
+        } else {
+            if !value.is_power_of_two() || value > 1 << MAX_P2ALIGN {
+                return Err(AssemblerError::Syntax {
+                    loc: self.loc(broken_line[1]),
+                    token: broken_line[1].to_string(),
+                    message: format!("alignment {value} is not a power of 2 up to 2^{MAX_P2ALIGN}")
+                });
+            }
+            value
+        };
+        let fill = self.fill_operand(broken_line, 2)?;
+        let max = match broken_line.get(3) {
+            Some(token) => Some(self.count(token)?),
+            None => None
+        };
+
+        let Some(target) = self.curr_address.checked_next_multiple_of(align) else {
+            return Err(AssemblerError::AddressOverflow {
+                loc: self.loc(broken_line[0]),
+                token: broken_line[0].to_string(),
+                address: self.curr_address
+            });
+        };
+        if max.is_some_and(|max| target - self.curr_address > max) {
+            return Ok(());
+        }
+        self.sections.align_current(align);
+        return self.pad(target, fill, broken_line[0]);
+    }
+
+    // .space/.zero size[, fill]
+    pub(crate) fn space(&mut self, broken_line : &[&str], fill : bool) -> TranslateResult {
+        self.check_operand_range(broken_line, 1, if fill { 2 } else { 1 })?;
+
+        let size = self.count(broken_line[1])?;
+        let fill = self.fill_operand(broken_line, 2)?.unwrap_or(0);
+        return self.fill_bytes(size, fill, broken_line[0]);
+    }
+
+    // .fill repeat[, size[, value]]
+    // Places repeat copies of the value in size bytes each, size is 1 and value 0 when left out.
+    pub(crate) fn fill(&mut self, broken_line : &[&str]) -> TranslateResult {
+        self.check_operand_range(broken_line, 1, 3)?;
+
+        let repeat = self.count(broken_line[1])?;
+        let size = match broken_line.get(2).filter(|token| !token.is_empty()) {
+            Some(token) => {
+                let size = self.count(token)?;
+                self.check_range(token, size as i64, 0, 8)?;
+                size as u32
+            },
+            None => 1
+        };
+        let value = match broken_line.get(3) {
+            Some(token) => self.imm(token)?,
+            None => 0
+        };
+
+        let Some(total) = repeat.checked_mul(size as u64) else {
+            return Err(AssemblerError::AddressOverflow {
+                loc: self.loc(broken_line[0]),
+                token: broken_line[0].to_string(),
+                address: self.curr_address
+            });
+        };
+        let zero = size == 0 || value as u64 & (u64::MAX >> (64 - size * 8)) == 0;
+        if self.skip(total, zero, broken_line[0])? {
+            return Ok(());
+        }
+        for _ in 0..repeat {
+            self.emit_value(value, size, broken_line[0])?;
+        }
+        Ok(())
+    }
+
+    // .equ name, value and .set name, value, also written `name = value`.
+    // A value that depends on an address, such as `. + 4`, moves with the code like a label.
+    // Only .set and `=` can give a symbol a new value, lines after it see the new one.
+    pub(crate) fn assign(&mut self, broken_line : &[&str], redefinable : bool) -> TranslateResult {
+        self.check_operands(broken_line, 2)?;
+
+        let value = self.eval(broken_line[2])?;
+        let kind = match value.is_address() {
+            true => SymbolKind::Relative,
+            false => SymbolKind::Absolute
+        };
+        return self.define(broken_line[1], broken_line[1], kind, value.val, redefinable);
+    }
+
+    // .text, .data, .rodata and .bss, which switch to the section of that name.
+    pub(crate) fn switch_section(&mut self, broken_line : &[&str]) -> TranslateResult {
+        self.check_operands(broken_line, 0)?;
+        self.enter_section(broken_line[0], None, broken_line[0]);
+        Ok(())
+    }
+
+    // .section name[, "flags"[, @type]]
+    // Flags are any of a, w and x, the type @progbits or @nobits. Without them the name decides,
+    // so `.section .text.start` holds code.
+    pub(crate) fn section(&mut self, broken_line : &[&str]) -> TranslateResult {
+        self.check_operand_range(broken_line, 1, 3)?;
+
+        let name = broken_line[1];
+        if name.is_empty() || name.contains(|c : char| c.is_whitespace() || c == '"') {
+            return Err(AssemblerError::Syntax {
+                loc: self.loc(broken_line[0]),
+                token: broken_line[0].to_string(),
+                message: format!("`{name}` is not a valid section name")
+            });
+        }
+        let flags = match broken_line.get(2) {
+            Some(flags) => Some(SectionFlags::parse(name, flags, broken_line.get(3).copied())
+                .map_err(|message| AssemblerError::Syntax { loc: self.loc(flags), token: flags.to_string(), message })?),
+            None => None
+        };
+
+        self.enter_section(name, flags, broken_line[0]);
+        Ok(())
+    }
+
+    // Carries on filling the named section, opening it if this is the first time.
+    // A section keeps the flags it was opened with.
+    fn enter_section(&mut self, name : &str, flags : Option<SectionFlags>, token : &str) {
+        if let Some(section) = self.sections.find(name)
+            && flags.is_some_and(|flags| flags != section.flags) {
+            self.warn("section-flags", token, format!("section `{name}` was opened with other flags, they are kept"));
+        }
+
+        let flags = flags.unwrap_or(SectionFlags::for_name(name));
+        self.curr_address = self.sections.switch(name, flags, self.loc(token), self.curr_address);
+    }
+}
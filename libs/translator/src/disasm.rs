@@ -0,0 +1,109 @@
+use data_structures::*;
+use crate::isa::{ABI_NAMES, RV32I, RV32I_SYSTEM};
+
+// How registers are written in disassembly
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegNames {
+    Numeric, // x0-x31
+    Abi // zero, ra, sp, ...
+}
+
+impl RegNames {
+    fn name(&self, reg : u32) -> String {
+        return match self {
+            RegNames::Numeric => format!("x{reg}"),
+            RegNames::Abi => ABI_NAMES[reg as usize].to_string()
+        };
+    }
+}
+
+// Sign-extends the low bits of a value.
+fn sign_extend(value : u32, bits : u32) -> i32 {
+    let shift = 32 - bits;
+    return ((value << shift) as i32) >> shift;
+}
+
+// Letters of a fence predecessor or successor set.
+fn fence_set(set : u32) -> String {
+    return "iorw".chars()
+        .enumerate()
+        .filter(|(i, _)| set & (0b1000 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect();
+}
+
+// Finds the mnemonic and type of an instruction word.
+fn lookup(word : u32) -> Option<(&'static str, &'static InstType)> {
+    let opcode = word & 0x7F;
+    let funct3 = (word >> 12) & 0x7;
+    let funct7 = word >> 25;
+
+    if opcode == 0x73 {
+        // Everything but funct12 is zero for the system instructions
+        return RV32I_SYSTEM.iter()
+            .find(|(_, funct12)| word == (funct12 << 20) | 0x73)
+            .map(|(name, _)| (*name, &InstType::I));
+    }
+
+    return RV32I.iter()
+        .find(|(_, inst_type, inst_opcode, inst_funct3, inst_funct7)| {
+            // The upper bits of shift immediates hold funct7
+            let has_funct7 = matches!(inst_type, InstType::R)
+                || (*inst_opcode == 0x13 && (*inst_funct3 == 1 || *inst_funct3 == 5));
+            let has_funct3 = !matches!(inst_type, InstType::U | InstType::J);
+
+            *inst_opcode == opcode
+                && (!has_funct3 || *inst_funct3 == funct3)
+                && (!has_funct7 || *inst_funct7 == funct7)
+        })
+        .map(|(name, inst_type, _, _, _)| (*name, inst_type));
+}
+
+// Disassembles one RV32I instruction into the syntax the translator reads.
+// Words that are not RV32I instructions come out as .word directives.
+pub fn disassemble(word : u32, names : RegNames) -> String {
+    let Some((name, inst_type)) = lookup(word) else {
+        return format!(".word {word:#010x}");
+    };
+
+    let rd = names.name((word >> 7) & 0x1F);
+    let rs1 = names.name((word >> 15) & 0x1F);
+    let rs2 = names.name((word >> 20) & 0x1F);
+    let opcode = word & 0x7F;
+
+    return match inst_type {
+        InstType::R => format!("{name} {rd}, {rs1}, {rs2}"),
+        InstType::I => {
+            let imm = sign_extend(word >> 20, 12);
+            match opcode {
+                0x03 => format!("{name} {rd}, {imm}({rs1})"),
+                0x0F => format!("{name} {}, {}", fence_set((word >> 24) & 0xF), fence_set((word >> 20) & 0xF)),
+                0x73 => name.to_string(),
+                0x13 if matches!(name, "slli" | "srli" | "srai") => format!("{name} {rd}, {rs1}, {}", imm & 0x1F),
+                _ => format!("{name} {rd}, {rs1}, {imm}")
+            }
+        },
+        InstType::S => {
+            let imm = sign_extend(((word >> 25) << 5) | ((word >> 7) & 0x1F), 12);
+            format!("{name} {rs2}, {imm}({rs1})")
+        },
+        InstType::B => {
+            // imm[12|10:5] rs2 rs1 funct3 imm[4:1|11]
+            let imm = ((word >> 31) << 12)
+                | (((word >> 25) & 0x3F) << 5)
+                | (((word >> 8) & 0xF) << 1)
+                | (((word >> 7) & 0x1) << 11);
+            format!("{name} {rs1}, {rs2}, {}", sign_extend(imm, 13))
+        },
+        InstType::U => format!("{name} {rd}, {:#x}", word >> 12),
+        InstType::J => {
+            // imm[20|10:1|11|19:12]
+            let imm = ((word >> 31) << 20)
+                | (word & 0x000FF000)
+                | (((word >> 20) & 0x1) << 11)
+                | (((word >> 21) & 0x3FF) << 1);
+            format!("{name} {rd}, {}", sign_extend(imm, 21))
+        },
+        _ => format!(".word {word:#010x}")
+    };
+}
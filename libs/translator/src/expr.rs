@@ -0,0 +1,251 @@
+use data_structures::str_to_signed;
+
+// Result of an expression. Addresses count how many label addresses are added into the value,
+// so `end - start` is a plain number (0) while `loop + 4` is an address (1).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Value {
+    pub val: i64,
+    pub addresses: i32
+}
+
+impl Value {
+    pub fn number(val : i64) -> Self {
+        Self { val, addresses: 0 }
+    }
+
+    pub fn address(val : i64) -> Self {
+        Self { val, addresses: 1 }
+    }
+
+    pub fn is_address(&self) -> bool {
+        return self.addresses != 0;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ExprError {
+    Undefined(String), // Symbol that is not defined
+    Invalid(String) // Description of what is wrong
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+    Open,
+    Close
+}
+
+// Longest first so that << is not read as <
+const OPERATORS: [&str; 21] = [
+    "<<", ">>", "&&", "||", "==", "!=", "<=", ">=", "<>",
+    "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "<", ">",
+];
+
+fn is_ident_char(c : char) -> bool {
+    return c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$';
+}
+
+// Value of a character literal body, such as `A` or `\n`.
+fn char_value(body : &str) -> Option<i64> {
+    let mut chars = body.chars();
+    let value = match (chars.next()?, chars.next()) {
+        ('\\', Some(escaped)) => match escaped {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' | '\'' | '"' => escaped,
+            _ => return None
+        },
+        (c, None) => c,
+        _ => return None
+    };
+    if chars.next().is_some() {
+        return None;
+    }
+    return Some(value as i64);
+}
+
+fn tokenize(src : &str) -> Result<Vec<Token>, ExprError> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut rest = src.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            rest = &rest[1..];
+        } else if c == '\'' {
+            // 'A', or an escape such as '\n' or '\''
+            let body_len = match rest[1..].strip_prefix('\\') {
+                Some(escaped) => 1 + escaped.chars().next().map_or(0, |c| c.len_utf8()),
+                None => rest[1..].chars().next().map_or(0, |c| c.len_utf8())
+            };
+            let body = &rest[1..1 + body_len];
+            if !rest[1 + body_len..].starts_with('\'') {
+                return Err(ExprError::Invalid("character literal is not closed".to_string()));
+            }
+            let value = char_value(body)
+                .ok_or_else(|| ExprError::Invalid(format!("`'{body}'` is not a character")))?;
+            tokens.push(Token::Num(value));
+            rest = &rest[body_len + 2..];
+        } else if is_ident_char(c) {
+            let len = rest.find(|c : char| !is_ident_char(c)).unwrap_or(rest.len());
+            let word = &rest[..len];
+            if c.is_ascii_digit() {
+                let value = str_to_signed(word)
+                    .map_err(|_| ExprError::Invalid(format!("`{word}` is not a number")))?;
+                tokens.push(Token::Num(value));
+            } else {
+                tokens.push(Token::Ident(word.to_string()));
+            }
+            rest = &rest[len..];
+        } else {
+            let op = OPERATORS.iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| ExprError::Invalid(format!("unexpected `{c}`")))?;
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        }
+        rest = rest.trim_start();
+    }
+
+    return Ok(tokens);
+}
+
+// Binding strength of binary operators, following GNU as:
+// * / % << >> bind tightest, then | & ^ !, then + - and comparisons, then && ||.
+fn precedence(op : &str) -> Option<u8> {
+    return match op {
+        "*" | "/" | "%" | "<<" | ">>" => Some(4),
+        "|" | "&" | "^" | "!" => Some(3),
+        "+" | "-" | "==" | "!=" | "<>" | "<" | "<=" | ">" | ">=" => Some(2),
+        "&&" | "||" => Some(1),
+        _ => None
+    };
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    lookup: &'a dyn Fn(&str) -> Option<Value>
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        return self.tokens.get(self.pos);
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        return token;
+    }
+
+    // A number, symbol, parenthesised expression or unary operator applied to one.
+    fn primary(&mut self) -> Result<Value, ExprError> {
+        return match self.next() {
+            Some(Token::Num(val)) => Ok(Value::number(val)),
+            Some(Token::Ident(name)) => (self.lookup)(&name).ok_or(ExprError::Undefined(name)),
+            Some(Token::Open) => {
+                let value = self.binary(1)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(value),
+                    _ => Err(ExprError::Invalid("missing `)`".to_string()))
+                }
+            },
+            Some(Token::Op(op)) => {
+                let value = self.primary()?;
+                match op {
+                    "+" => Ok(value),
+                    "-" => Ok(Value { val: value.val.wrapping_neg(), addresses: -value.addresses }),
+                    "~" => Ok(Value::number(!number(value, op)?)),
+                    "!" => Ok(Value::number((number(value, op)? == 0) as i64)),
+                    _ => Err(ExprError::Invalid(format!("`{op}` needs a value on its left")))
+                }
+            },
+            Some(Token::Close) => Err(ExprError::Invalid("unexpected `)`".to_string())),
+            None => Err(ExprError::Invalid("expected a value".to_string()))
+        };
+    }
+
+    // Precedence climbing over the binary operators binding at least as tightly as min_prec.
+    fn binary(&mut self, min_prec : u8) -> Result<Value, ExprError> {
+        let mut lhs = self.primary()?;
+
+        while let Some(Token::Op(op)) = self.peek() {
+            let op: &'static str = op;
+            let prec = match precedence(op) {
+                Some(prec) if prec >= min_prec => prec,
+                _ => break
+            };
+            self.pos += 1;
+            let rhs = self.binary(prec + 1)?;
+            lhs = apply(op, lhs, rhs)?;
+        }
+
+        return Ok(lhs);
+    }
+}
+
+// Only + and - may be applied to addresses.
+fn number(value : Value, op : &str) -> Result<i64, ExprError> {
+    if value.is_address() {
+        return Err(ExprError::Invalid(format!("`{op}` cannot be applied to an address")));
+    }
+    return Ok(value.val);
+}
+
+fn apply(op : &str, lhs : Value, rhs : Value) -> Result<Value, ExprError> {
+    match op {
+        "+" => return Ok(Value { val: lhs.val.wrapping_add(rhs.val), addresses: lhs.addresses + rhs.addresses }),
+        "-" => return Ok(Value { val: lhs.val.wrapping_sub(rhs.val), addresses: lhs.addresses - rhs.addresses }),
+        _ => {}
+    }
+
+    let (a, b) = (number(lhs, op)?, number(rhs, op)?);
+    // Comparisons give all ones when true, like GNU as
+    let truth = |cond : bool| if cond { -1 } else { 0 };
+    let val = match op {
+        "*" => a.wrapping_mul(b),
+        "/" | "%" if b == 0 => return Err(ExprError::Invalid("division by zero".to_string())),
+        "/" => a.wrapping_div(b),
+        "%" => a.wrapping_rem(b),
+        "<<" | ">>" if !(0..64).contains(&b) =>
+            return Err(ExprError::Invalid(format!("shift amount {b} is out of range"))),
+        "<<" => a << b,
+        ">>" => ((a as u64) >> b) as i64,
+        "&" => a & b,
+        "|" => a | b,
+        "^" => a ^ b,
+        "!" => a | !b,
+        "==" => truth(a == b),
+        "!=" | "<>" => truth(a != b),
+        "<" => truth(a < b),
+        "<=" => truth(a <= b),
+        ">" => truth(a > b),
+        ">=" => truth(a >= b),
+        "&&" => (a != 0 && b != 0) as i64,
+        "||" => (a != 0 || b != 0) as i64,
+        _ => return Err(ExprError::Invalid(format!("unknown operator `{op}`")))
+    };
+    return Ok(Value::number(val));
+}
+
+// Evaluates a constant expression. Symbols are resolved through lookup.
+pub fn eval(src : &str, lookup : &dyn Fn(&str) -> Option<Value>) -> Result<Value, ExprError> {
+    let mut parser = Parser { tokens: tokenize(src)?, pos: 0, lookup };
+    let value = parser.binary(1)?;
+
+    if let Some(token) = parser.peek() {
+        return Err(ExprError::Invalid(match token {
+            Token::Close => "unexpected `)`".to_string(),
+            _ => "expected an operator".to_string()
+        }));
+    }
+    if value.addresses != 0 && value.addresses != 1 {
+        return Err(ExprError::Invalid("addresses can only be subtracted from one another".to_string()));
+    }
+    return Ok(value);
+}
@@ -0,0 +1,361 @@
+use std::fmt;
+use data_structures::*;
+use serde::{Deserialize, Deserializer};
+use crate::Xlen;
+
+// ABI names of x0-x31
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+// RV32I base instructions, except the system instructions which only differ in funct12.
+// (mnemonic, type, opcode, funct3, funct7)
+pub(crate) const RV32I: [(&str, InstType, u32, u32, u32); 38] = [
+    ("lui", InstType::U, 0x37, 0, 0),
+    ("auipc", InstType::U, 0x17, 0, 0),
+    ("jal", InstType::J, 0x6F, 0, 0),
+    ("jalr", InstType::I, 0x67, 0, 0),
+    ("beq", InstType::B, 0x63, 0, 0),
+    ("bne", InstType::B, 0x63, 1, 0),
+    ("blt", InstType::B, 0x63, 4, 0),
+    ("bge", InstType::B, 0x63, 5, 0),
+    ("bltu", InstType::B, 0x63, 6, 0),
+    ("bgeu", InstType::B, 0x63, 7, 0),
+    ("lb", InstType::I, 0x03, 0, 0),
+    ("lh", InstType::I, 0x03, 1, 0),
+    ("lw", InstType::I, 0x03, 2, 0),
+    ("lbu", InstType::I, 0x03, 4, 0),
+    ("lhu", InstType::I, 0x03, 5, 0),
+    ("sb", InstType::S, 0x23, 0, 0),
+    ("sh", InstType::S, 0x23, 1, 0),
+    ("sw", InstType::S, 0x23, 2, 0),
+    ("addi", InstType::I, 0x13, 0, 0),
+    ("slti", InstType::I, 0x13, 2, 0),
+    ("sltiu", InstType::I, 0x13, 3, 0),
+    ("xori", InstType::I, 0x13, 4, 0),
+    ("ori", InstType::I, 0x13, 6, 0),
+    ("andi", InstType::I, 0x13, 7, 0),
+    ("slli", InstType::I, 0x13, 1, 0),
+    ("srli", InstType::I, 0x13, 5, 0),
+    ("srai", InstType::I, 0x13, 5, 0x20),
+    ("add", InstType::R, 0x33, 0, 0),
+    ("sub", InstType::R, 0x33, 0, 0x20),
+    ("sll", InstType::R, 0x33, 1, 0),
+    ("slt", InstType::R, 0x33, 2, 0),
+    ("sltu", InstType::R, 0x33, 3, 0),
+    ("xor", InstType::R, 0x33, 4, 0),
+    ("srl", InstType::R, 0x33, 5, 0),
+    ("sra", InstType::R, 0x33, 5, 0x20),
+    ("or", InstType::R, 0x33, 6, 0),
+    ("and", InstType::R, 0x33, 7, 0),
+    ("fence", InstType::I, 0x0F, 0, 0),
+];
+
+// (mnemonic, funct12)
+pub(crate) const RV32I_SYSTEM: [(&str, u32); 2] = [
+    ("ecall", 0),
+    ("ebreak", 1),
+];
+
+// The complete RV32I base instruction table.
+pub fn rv32i() -> Vec<ExtractedData<Vec<Inst>>> {
+    let mut table: Vec<ExtractedData<Vec<Inst>>> = RV32I.iter()
+        .map(|(name, inst_type, opcode, funct3, funct7)| {
+            ExtractedData::new(name, vec![Inst::new(inst_type.clone(), *opcode, *funct3, *funct7)])
+        })
+        .collect();
+
+    for (name, funct12) in RV32I_SYSTEM.iter() {
+        table.push(ExtractedData::new(name, vec![Inst::system(*funct12)]));
+    }
+    return table;
+}
+
+// Register width of an ISA name and whether it has the C extension, None when it is not built in.
+// rv32ic, rv64i and rv64ic share the RV32I instruction table, their width and compressed
+// instructions are only used by li and for the address space.
+pub fn target(isa : &str) -> Option<(Xlen, bool)> {
+    return match isa.to_ascii_lowercase().as_str() {
+        "rv32i" => Some((Xlen::Rv32, false)),
+        "rv32ic" => Some((Xlen::Rv32, true)),
+        "rv64i" => Some((Xlen::Rv64, false)),
+        "rv64ic" => Some((Xlen::Rv64, true)),
+        _ => None
+    };
+}
+
+// Whether a target has the extension an instruction belongs to. The base I is always there and C
+// only in the compressed targets, no other single-letter extension is built in. Longer names, such
+// as Xlab or Zicond, are extensions that spec files add.
+pub fn has_extension(extension : &str, compressed : bool) -> bool {
+    return match extension.to_ascii_uppercase().as_str() {
+        "I" => true,
+        "C" => compressed,
+        other => other.len() > 1
+    };
+}
+
+// Instruction table for an ISA name, None when it is not built in.
+pub fn instructions(isa : &str) -> Option<Vec<ExtractedData<Vec<Inst>>>> {
+    return target(isa).map(|_| rv32i());
+}
+
+// A pseudo-instruction, written as the instructions it stands for.
+// $1, $2 and $3 in the expansion are replaced by the operands it is given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pseudo {
+    pub operands: usize,
+    pub expansion: Vec<String>
+}
+
+// Pseudo-instructions from the RISC-V assembly manual.
+// li has no expansion here, the translator picks one for its value with li::sequence.
+// The jalr and addi after an auipc take the low part of the offset through `%pcrel_lo(. - 4)`,
+// as the auipc is the instruction before them.
+// (mnemonic, operand count, expansion)
+pub(crate) const PSEUDO: [(&str, usize, &[&str]); 26] = [
+    ("nop", 0, &["addi x0, x0, 0"]),
+    ("mv", 2, &["addi $1, $2, 0"]),
+    ("not", 2, &["xori $1, $2, -1"]),
+    ("neg", 2, &["sub $1, x0, $2"]),
+    ("seqz", 2, &["sltiu $1, $2, 1"]),
+    ("snez", 2, &["sltu $1, x0, $2"]),
+    ("sltz", 2, &["slt $1, $2, x0"]),
+    ("sgtz", 2, &["slt $1, x0, $2"]),
+    ("beqz", 2, &["beq $1, x0, $2"]),
+    ("bnez", 2, &["bne $1, x0, $2"]),
+    ("blez", 2, &["bge x0, $1, $2"]),
+    ("bgez", 2, &["bge $1, x0, $2"]),
+    ("bltz", 2, &["blt $1, x0, $2"]),
+    ("bgtz", 2, &["blt x0, $1, $2"]),
+    ("bgt", 3, &["blt $2, $1, $3"]),
+    ("ble", 3, &["bge $2, $1, $3"]),
+    ("bgtu", 3, &["bltu $2, $1, $3"]),
+    ("bleu", 3, &["bgeu $2, $1, $3"]),
+    ("j", 1, &["jal x0, $1"]),
+    ("jr", 1, &["jalr x0, $1, 0"]),
+    ("ret", 0, &["jalr x0, x1, 0"]),
+    ("call", 1, &["auipc x1, %pcrel_hi($1)", "jalr x1, x1, %pcrel_lo(. - 4)"]),
+    ("tail", 1, &["auipc x6, %pcrel_hi($1)", "jalr x0, x6, %pcrel_lo(. - 4)"]),
+    ("la", 2, &["auipc $1, %pcrel_hi($2)", "addi $1, $1, %pcrel_lo(. - 4)"]),
+    ("lla", 2, &["auipc $1, %pcrel_hi($2)", "addi $1, $1, %pcrel_lo(. - 4)"]),
+    ("li", 2, &[]),
+];
+
+// The pseudo-instruction table.
+pub fn pseudo_instructions() -> Vec<ExtractedData<Pseudo>> {
+    return PSEUDO.iter()
+        .map(|(name, operands, expansion)| ExtractedData::new(name, Pseudo {
+            operands: *operands,
+            expansion: expansion.iter().map(|line| line.to_string()).collect()
+        }))
+        .collect();
+}
+
+// The register table, x0-x31 along with their ABI names and fp, another name for s0.
+pub fn registers() -> Vec<ExtractedData<Reg>> {
+    let mut table: Vec<ExtractedData<Reg>> = (0..32)
+        .map(|num| ExtractedData::new(&format!("x{num}"), Reg { reg_num: num }))
+        .collect();
+
+    for (num, name) in ABI_NAMES.iter().enumerate() {
+        table.push(ExtractedData::new(name, Reg { reg_num: num as u32 }));
+    }
+    table.push(ExtractedData::new("fp", Reg { reg_num: 8 }));
+    return table;
+}
+
+// One instruction of an ISA spec file. Numbers may be JSON numbers or strings such as "0x0B".
+//
+// {"mnemonic": "mac", "format": "R", "opcode": "0x0B", "funct3": 0, "funct7": 1,
+//  "operands": "rd, rs1, rs2", "extension": "Xlab"}
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstSpec {
+    pub mnemonic: String,
+    pub format: InstType,
+    #[serde(deserialize_with = "field")]
+    pub opcode: u32,
+    #[serde(default, deserialize_with = "opt_field")]
+    pub funct3: Option<u32>,
+    #[serde(default, deserialize_with = "opt_field")]
+    pub funct7: Option<u32>,
+    #[serde(default, deserialize_with = "opt_field")]
+    pub funct12: Option<u32>, // Only for system instructions
+    pub operands: String, // Operand syntax, e.g. "rd, imm(rs1)"
+    pub extension: String // Extension the instruction belongs to, e.g. "I" or "Xlab"
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Field { Num(u32), Text(String) }
+
+fn field<'de, D : Deserializer<'de>>(deserializer : D) -> Result<u32, D::Error> {
+    return match Field::deserialize(deserializer)? {
+        Field::Num(num) => Ok(num),
+        Field::Text(text) => str_to_int(&text)
+            .map_err(|_| serde::de::Error::custom(format!("`{text}` is not a number")))
+    };
+}
+
+fn opt_field<'de, D : Deserializer<'de>>(deserializer : D) -> Result<Option<u32>, D::Error> {
+    return field(deserializer).map(Some);
+}
+
+// Error in an ISA spec. Entry is the 1-based position of the instruction, or 0 for the whole file.
+#[derive(Debug, PartialEq)]
+pub struct SpecError {
+    pub entry: usize,
+    pub message: String
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.entry == 0 {
+            return write!(f, "{}", self.message);
+        }
+        return write!(f, "instruction {}: {}", self.entry, self.message);
+    }
+}
+
+impl InstSpec {
+    // Operand syntax the translator encodes for this format and opcode
+    fn expected_operands(&self) -> &'static str {
+        return match (&self.format, self.opcode) {
+            (InstType::R, _) => "rd, rs1, rs2",
+            (InstType::I, 0x03) => "rd, imm(rs1)",
+            (InstType::I, 0x0F) => "pred, succ",
+            (InstType::I, 0x73) => "",
+            (InstType::I, _) => "rd, rs1, imm",
+            (InstType::S, _) => "rs2, imm(rs1)",
+            (InstType::B, _) => "rs1, rs2, offset",
+            (InstType::U, _) => "rd, imm",
+            (InstType::J, _) => "rd, offset",
+            (_, _) => ""
+        };
+    }
+
+    // Bits fixed by the instruction and their values, (mask, bits)
+    pub fn encoding(&self) -> (u32, u32) {
+        let inst = self.inst();
+        let mut mask: u32 = 0x7F;
+        if self.funct3.is_some() {
+            mask |= 0x7 << 12;
+        }
+        if self.funct7.is_some() {
+            mask |= 0x7F << 25;
+        }
+        if self.funct12.is_some() {
+            mask |= 0xFFF << 20;
+        }
+        return (mask, inst.opcode | inst.funct3 | inst.funct7 | inst.funct12);
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let name = &self.mnemonic;
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_') {
+            return Err(format!("`{name}` is not a valid mnemonic"));
+        }
+        if matches!(self.format, InstType::META | InstType::PSEUDO) {
+            return Err(format!("`{name}` must have format R, I, S, B, U or J"));
+        }
+        if self.opcode > 0x7F || self.opcode & 0b11 != 0b11 {
+            return Err(format!("`{name}` has opcode {:#X}, which is not a 32-bit opcode", self.opcode));
+        }
+
+        // Which function fields each format has. Of the I-types only shifts by an immediate have
+        // a funct7, any other would have it overwritten by the top of the immediate.
+        let shift = self.opcode == 0x13 && matches!(self.funct3, Some(1 | 5));
+        let (funct3, funct7, funct12) = match self.format {
+            InstType::R => (true, true, false),
+            InstType::I => (true, shift, self.opcode == 0x73),
+            InstType::S | InstType::B => (true, false, false),
+            _ => (false, false, false)
+        };
+        for (value, allowed, field, max) in [
+            (self.funct3, funct3, "funct3", 0x7),
+            (self.funct7, funct7, "funct7", 0x7F),
+            (self.funct12, funct12, "funct12", 0xFFF)
+        ] {
+            match value {
+                Some(_) if !allowed => return Err(format!("`{name}` cannot have a {field} field")),
+                Some(value) if value > max => return Err(format!("`{name}` has {field} {value:#X}, above {max:#X}")),
+                _ => {}
+            }
+        }
+        if funct3 && self.funct3.is_none() {
+            return Err(format!("`{name}` is missing its funct3 field"));
+        }
+        if matches!(self.format, InstType::R) && self.funct7.is_none() {
+            return Err(format!("`{name}` is missing its funct7 field"));
+        }
+
+        let normalize = |syntax : &str| syntax.split_whitespace().collect::<String>();
+        let expected = self.expected_operands();
+        if normalize(&self.operands) != normalize(expected) {
+            return Err(format!("`{name}` takes operands `{}`, but the translator encodes `{expected}` for it",
+                self.operands));
+        }
+        return Ok(());
+    }
+
+    pub fn inst(&self) -> Inst {
+        let mut inst = Inst::new(self.format.clone(), self.opcode, self.funct3.unwrap_or(0), self.funct7.unwrap_or(0));
+        inst.funct12 = (self.funct12.unwrap_or(0) & 0xFFF) << 20;
+        return inst;
+    }
+}
+
+// Reads an ISA spec, a JSON list of instructions.
+// Rejects malformed entries, repeated mnemonics and instructions that cannot be told apart.
+pub fn parse_spec(content : &str) -> Result<Vec<InstSpec>, SpecError> {
+    let specs: Vec<InstSpec> = serde_json::from_str(content)
+        .map_err(|err| SpecError { entry: 0, message: err.to_string() })?;
+
+    for (i, spec) in specs.iter().enumerate() {
+        spec.validate().map_err(|message| SpecError { entry: i + 1, message })?;
+
+        let (mask, bits) = spec.encoding();
+        for other in specs[..i].iter() {
+            if other.mnemonic.eq_ignore_ascii_case(&spec.mnemonic) {
+                return Err(SpecError { entry: i + 1,
+                    message: format!("`{}` is defined more than once", spec.mnemonic) });
+            }
+
+            // Two instructions collide when every bit both of them fix is the same
+            let (other_mask, other_bits) = other.encoding();
+            if (bits ^ other_bits) & mask & other_mask == 0 {
+                return Err(SpecError { entry: i + 1,
+                    message: format!("`{}` ({}) has the same encoding as `{}` ({})",
+                        spec.mnemonic, spec.extension, other.mnemonic, other.extension) });
+            }
+        }
+    }
+    return Ok(specs);
+}
+
+// Bits fixed by an instruction of the table and their values, (mask, bits).
+// None for directives and pseudo-instructions, which have no encoding of their own.
+pub fn encoding(inst : &Inst) -> Option<(u32, u32)> {
+    let funct3 = inst.funct3 >> 12;
+    let mask: u32 = match inst.inst_type {
+        InstType::R => 0xFE00707F,
+        // System instructions are told apart by funct12, shifts by immediate by funct7
+        InstType::I if inst.opcode == 0x73 => 0xFFF0707F,
+        InstType::I if inst.opcode == 0x13 && (funct3 == 1 || funct3 == 5) => 0xFE00707F,
+        InstType::I | InstType::S | InstType::B => 0x707F,
+        InstType::U | InstType::J => 0x7F,
+        InstType::META | InstType::PSEUDO => return None
+    };
+    return Some((mask, inst.opcode | inst.funct3 | inst.funct7 | inst.funct12));
+}
+
+// Instruction table built from a parsed spec.
+pub fn spec_table(specs : &[InstSpec]) -> Vec<ExtractedData<Vec<Inst>>> {
+    return specs.iter()
+        .map(|spec| ExtractedData::new(&spec.mnemonic, vec![spec.inst()]))
+        .collect();
+}
@@ -0,0 +1,131 @@
+use crate::{Xlen, high20, low12};
+
+// One instruction of a li sequence. Only the first instruction reads x0, the rest build on rd.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    Lui(i64), // Upper 20 bits
+    Addi(i64),
+    Addiw(i64), // Adds and sign-extends from bit 31, RV64 only
+    Slli(u32),
+    Srli(u32)
+}
+
+// Sign-extends the low bits of a value.
+fn sign_extend(value : i64, bits : u32) -> i64 {
+    let shift = 64 - bits;
+    return (value << shift) >> shift;
+}
+
+fn build(value : i64, xlen : Xlen) -> Vec<Step> {
+    let mut steps: Vec<Step> = Vec::new();
+    if xlen == Xlen::Rv32 || value == value as i32 as i64 {
+        let value = value as i32 as i64;
+        let hi20 = high20(value);
+        let lo12 = low12(value);
+
+        if hi20 != 0 {
+            steps.push(Step::Lui(hi20));
+        }
+        if lo12 != 0 || hi20 == 0 {
+            // On RV64 lui sign-extends, so the add has to wrap at 32 bits as well
+            steps.push(match xlen {
+                Xlen::Rv64 if hi20 != 0 => Step::Addiw(lo12),
+                _ => Step::Addi(lo12)
+            });
+        }
+        return steps;
+    }
+
+    // Builds the upper bits, shifts them into place and adds the low 12 bits.
+    // Trailing zeros of the upper part are folded into the shift.
+    let lo12 = low12(value);
+    let hi52 = (value as u64).wrapping_add(0x800) >> 12;
+    let shift = 12 + hi52.trailing_zeros();
+    let upper = sign_extend((hi52 >> (shift - 12)) as i64, 64 - shift);
+
+    steps = build(upper, xlen);
+    steps.push(Step::Slli(shift));
+    if lo12 != 0 {
+        steps.push(Step::Addi(lo12));
+    }
+
+    // A value with leading zeros can also be built shifted to the top and shifted back down,
+    // with ones or zeros coming in below. 0xFFFFFFFF is -1 shifted right by 32.
+    if value > 0 {
+        let zeros = value.leading_zeros();
+        for shifted in [(value << zeros) | ((1 << zeros) - 1), value << zeros] {
+            let mut other = build(shifted, xlen);
+            other.push(Step::Srli(zeros));
+            if other.len() < steps.len() {
+                steps = other;
+            }
+        }
+    }
+    return steps;
+}
+
+// Sequence of instructions that loads the value into a register. Values that fit in 32 bits take
+// lui and addi, wider ones the shorter of building the upper bits and shifting them up with slli,
+// or building the value shifted to the top and shifting it down with srli.
+// On RV32 the value is taken modulo 2^32.
+pub fn sequence(value : i64, xlen : Xlen) -> Vec<Step> {
+    return build(value, xlen);
+}
+
+// Compressed form of a step, when there is one. rs is the register the step reads.
+fn compress(step : Step, rd : u32, rs : u32, xlen : Xlen) -> Option<u32> {
+    // Six bit immediates are split as imm[5] at bit 12 and imm[4:0] at bits 6:2
+    let ci = |funct3 : u32, imm : i64, op : u32| -> u32 {
+        let imm = imm as u32;
+        return (funct3 << 13) | (((imm >> 5) & 1) << 12) | (rd << 7) | ((imm & 0x1F) << 2) | op;
+    };
+    let small = |imm : i64| (-32..32).contains(&imm);
+
+    if rd == 0 {
+        return None;
+    }
+    return match step {
+        Step::Addi(imm) if rs == 0 && small(imm) => Some(ci(0b010, imm, 0b01)), // c.li
+        Step::Addi(imm) if rs == rd && imm != 0 && small(imm) => Some(ci(0b000, imm, 0b01)), // c.addi
+        Step::Addiw(imm) if xlen == Xlen::Rv64 && small(imm) => Some(ci(0b001, imm, 0b01)), // c.addiw
+        Step::Lui(hi20) => {
+            // c.lui cannot write sp, the encoding is taken by c.addi16sp
+            let imm = sign_extend(hi20, 20);
+            match rd != 2 && imm != 0 && small(imm) {
+                true => Some(ci(0b011, imm, 0b01)),
+                false => None
+            }
+        },
+        Step::Slli(shamt) if shamt != 0 && (xlen == Xlen::Rv64 || shamt < 32) => Some(ci(0b000, shamt as i64, 0b10)), // c.slli
+        Step::Srli(shamt) if shamt != 0 && (xlen == Xlen::Rv64 || shamt < 32) && (8..16).contains(&rd) => {
+            // c.srli only reaches x8 to x15, as rd' in bits 9:7
+            Some((0b100 << 13) | (((shamt >> 5) & 1) << 12) | ((rd - 8) << 7) | ((shamt & 0x1F) << 2) | 0b01)
+        },
+        _ => None
+    };
+}
+
+// Encodes a sequence for rd, giving each instruction word and its size in bytes.
+// With compression on, instructions that have a 16-bit form use it.
+pub fn encode(steps : &[Step], rd : u32, xlen : Xlen, compressed : bool) -> Vec<(u32, u8)> {
+    let mut out: Vec<(u32, u8)> = Vec::new();
+
+    for (i, step) in steps.iter().enumerate() {
+        // lui reads nothing, so a sequence starting with it builds on rd from then on
+        let rs = if i == 0 { 0 } else { rd };
+        if compressed && let Some(word) = compress(*step, rd, rs, xlen) {
+            out.push((word, 2));
+            continue;
+        }
+
+        let word = match step {
+            Step::Lui(hi20) => ((*hi20 as u32 & 0xFFFFF) << 12) | (rd << 7) | 0x37,
+            Step::Addi(imm) => ((*imm as u32 & 0xFFF) << 20) | (rs << 15) | (rd << 7) | 0x13,
+            Step::Addiw(imm) => ((*imm as u32 & 0xFFF) << 20) | (rs << 15) | (rd << 7) | 0x1B,
+            Step::Slli(shamt) => (shamt << 20) | (rs << 15) | (1 << 12) | (rd << 7) | 0x13,
+            Step::Srli(shamt) => (shamt << 20) | (rs << 15) | (5 << 12) | (rd << 7) | 0x13
+        };
+        out.push((word, 4));
+    }
+    return out;
+}
@@ -1,12 +1,185 @@
+#![allow(clippy::needless_return)]
 
-use std::{mem::transmute, vec};
+pub mod diagnostics;
+pub mod directives;
+pub mod disasm;
+pub mod expr;
+pub mod isa;
+pub mod li;
+pub mod sections;
+pub mod symbols;
 
+use std::collections::BTreeMap;
+use std::rc::Rc;
 use data_structures::*;
+pub use diagnostics::{Diagnostic, Severity};
+use sections::{Section, Sections};
+use symbols::{Symbol, SymbolKind, SymbolTable};
+
+// Size of an instruction in bytes
+const INST_SIZE : i64 = 4;
+
+// Layout passes to run before giving up on the labels settling
+const MAX_LAYOUT_PASSES : usize = 8;
+
+// Branch and jump offsets are encoded in units of 2 bytes, so they must be even
+const BRANCH_ALIGN : i64 = 2;
+
+// Width of the address space
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Xlen { Rv32, Rv64 }
+
+impl Xlen {
+    // Highest valid address
+    pub fn max_address(&self) -> Address {
+        return match self {
+            Xlen::Rv32 => u32::MAX as Address,
+            Xlen::Rv64 => u64::MAX
+        };
+    }
+}
+
+type TranslateResult = Result<(), AssemblerError>;
+
+// Characters of a line outside character literals and strings, with the parenthesis depth at each.
+// Only the opening quote of a literal is kept.
+fn unquoted(line : &str) -> Vec<(usize, char, i32)> {
+    let mut out: Vec<(usize, char, i32)> = Vec::new();
+    let mut depth = 0;
+    let mut chars = line.char_indices();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // Skips over 'c' and '\c'
+            '\'' => {
+                if let Some((_, '\\')) = chars.next() {
+                    chars.next();
+                }
+                chars.next();
+            },
+            // Skips to the closing quote of "string", past escaped quotes
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => { chars.next(); },
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            },
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        out.push((i, c, depth));
+    }
+    return out;
+}
+
+// Splits a line into its label, mnemonic and operands, leaving out any comment.
+// Operands are separated by commas, so they may contain spaces, e.g. `BUF_SIZE * 4 - 1`,
+// and strings keep their spaces, commas and #.
+fn split_line(line : &str) -> Vec<&str> {
+    // Comments start with #
+    let code_end = unquoted(line).iter()
+        .find(|(_, c, _)| *c == '#')
+        .map_or(line.len(), |(i, _, _)| *i);
+    let mut rest = line[..code_end].trim();
+    let mut parts: Vec<&str> = Vec::new();
+
+    // A label may stand alone or precede an instruction on the same line
+    if let Some(first) = rest.split_whitespace().next()
+        && first.ends_with(':') {
+        parts.push(first);
+        rest = rest[first.len()..].trim_start();
+    }
+
+    // `name = value` is split as `= name, value`, so it reads like .set
+    if let Some(eq) = rest.find('=')
+        && !rest[eq + 1..].starts_with('=')
+        && symbols::valid_name(rest[..eq].trim()) {
+        parts.extend([&rest[eq..eq + 1], rest[..eq].trim(), rest[eq + 1..].trim()]);
+        return parts;
+    }
+
+    let mnemonic_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    if mnemonic_end > 0 {
+        parts.push(&rest[..mnemonic_end]);
+    }
+    let operands = rest[mnemonic_end..].trim();
+    if operands.is_empty() {
+        return parts;
+    }
+
+    // Empty operands are kept, they leave out an optional one, e.g. `.p2align 4,,15`
+    let mut start = 0;
+    for (i, c, depth) in unquoted(operands) {
+        if c == ',' && depth == 0 {
+            parts.push(operands[start..i].trim());
+            start = i + 1;
+        }
+    }
+    parts.push(operands[start..].trim());
+    return parts;
+}
+
+// Byte offset of a token in the line it was split from. Tokens from split_line, and the parts of
+// them operands are broken into, are slices of the line, so their place in it is where they start.
+// None for text that is not part of the line, such as the expansion of a pseudo-instruction.
+fn offset_in(line : &str, token : &str) -> Option<usize> {
+    let offset = (token.as_ptr() as usize).checked_sub(line.as_ptr() as usize)?;
+    return (offset + token.len() <= line.len()).then_some(offset);
+}
+
+// Splits an operand written with a relocation modifier, such as `%hi(symbol)`, into the
+// modifier name and its expression. None when the operand is not wrapped in one.
+fn split_modifier(token : &str) -> Option<(&str, &str)> {
+    let rest = token.strip_prefix('%')?;
+    let open = rest.find('(')?;
+    let name = &rest[..open];
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+
+    // The parenthesis after the name has to close at the end of the operand
+    let close = unquoted(&rest[open..]).iter()
+        .find(|(_, c, depth)| *c == ')' && *depth == 0)
+        .map(|(i, _, _)| open + *i)?;
+    if close != rest.len() - 1 {
+        return None;
+    }
+    return Some((name, rest[open + 1..close].trim()));
+}
+
+// Sign-extended low 12 bits of a value, the part added by an I or S-type immediate.
+pub(crate) fn low12(value : i64) -> i64 {
+    return ((value & 0xFFF) ^ 0x800) - 0x800;
+}
+
+// Upper 20 bits of a value, rounded so that adding the sign-extended low 12 bits gives it back.
+pub(crate) fn high20(value : i64) -> i64 {
+    return (value.wrapping_add(0x800) >> 12) & 0xFFFFF;
+}
+
 pub struct  Translator <'a> {
     inst_hm : HashMap<Vec<Inst>>, // HashMap with instructions
     reg_hm: HashMap<Reg>, // Hashmap with registers
-    label_hm: HashMap<u16>, // Hashmap of labels
-    curr_address : u16,
+    pseudo_hm: HashMap<isa::Pseudo>, // Expansions of the pseudo-instructions, marked PSEUDO in inst_hm
+    encodings: Vec<(String, u32, u32)>, // (mnemonic, mask, bits) of every instruction in inst_hm, see isa::encoding
+    symbols: SymbolTable, // Labels and constants, with those of the previous pass for forward references
+    pcrel_hm: BTreeMap<Address, String>, // Target of the %pcrel_hi in the auipc at each address
+    prev_pcrel_hm: BTreeMap<Address, String>, // The same for the previous pass
+    layout_pass : bool, // Passes before the last only place labels, so unresolved labels are allowed
+    layout_changed : bool, // Whether a label moved since the previous pass
+    compressed : bool, // Whether the C extension may be used
+    xlen : Xlen,
+    sections : Sections, // Where each section is placed, curr_address is the counter of the current one
+    curr_address : Address,
+    line_idx : usize, // Index of the line being translated in the data interface
+    line_text : Rc<str>, // The line being translated, the tokens being parsed are slices of it
+    expansion_column : Option<usize>, // Column of the pseudo-instruction being expanded, for tokens it made up
+    errors : Vec<AssemblerError>, // Errors from the last parse_file
+    diagnostics : Vec<Diagnostic>, // Errors and warnings from the last parse_file
     di: &'a mut DataInterface
 }
 
@@ -15,319 +188,1899 @@ impl <'a> Translator <'a> {
         Self {
             inst_hm : HashMap::new(inst_size),
             reg_hm : HashMap::new(reg_size),
-            label_hm : HashMap::new(16),
+            pseudo_hm : HashMap::new(32),
+            encodings : Vec::new(),
+            symbols : SymbolTable::new(),
+            pcrel_hm : BTreeMap::new(),
+            prev_pcrel_hm : BTreeMap::new(),
+            layout_pass : true,
+            layout_changed : false,
+            compressed : false,
+            xlen : Xlen::Rv32,
+            sections : Sections::new(),
             di : __di,
-            curr_address: 0
+            curr_address: 0,
+            line_idx: 0,
+            line_text: Rc::from(""),
+            expansion_column: None,
+            errors: Vec::new(),
+            diagnostics: Vec::new()
+        }
+    }
+
+    // Translator with the built-in RV32I instruction and register tables.
+    pub fn with_rv32i(di : &'a mut DataInterface) -> Self {
+        let mut translator = Self::new(64, 64, di);
+        translator.initialize(&isa::rv32i(), &isa::registers());
+        translator.add_pseudo(&isa::pseudo_instructions());
+        return translator;
+    }
+
+    // Adds the instructions of an ISA spec file, see isa::parse_spec.
+    // Mnemonics and encodings already in the table cannot be redefined, and each instruction must
+    // belong to an extension the target has, see isa::has_extension. Returns how many were added.
+    pub fn load_spec(&mut self, content : &str) -> Result<usize, isa::SpecError> {
+        let specs = isa::parse_spec(content)?;
+        for (i, spec) in specs.iter().enumerate() {
+            if !isa::has_extension(&spec.extension, self.compressed) {
+                return Err(isa::SpecError { entry: i + 1,
+                    message: format!("`{}` belongs to extension `{}`, which the target does not have",
+                        spec.mnemonic, spec.extension) });
+            }
+            if self.inst_hm.get(&spec.mnemonic).is_some() {
+                return Err(isa::SpecError { entry: i + 1,
+                    message: format!("`{}` is already an instruction", spec.mnemonic) });
+            }
+
+            // Two instructions collide when every bit both of them fix is the same
+            let (mask, bits) = spec.encoding();
+            let collision = self.encodings.iter()
+                .find(|(_, other_mask, other_bits)| (bits ^ other_bits) & mask & other_mask == 0);
+            if let Some((other, _, _)) = collision {
+                return Err(isa::SpecError { entry: i + 1,
+                    message: format!("`{}` ({}) has the same encoding as `{other}`", spec.mnemonic, spec.extension) });
+            }
+        }
+
+        let table = isa::spec_table(&specs);
+        self.add_encodings(&table);
+        self.inst_hm.build(&table);
+        return Ok(specs.len());
+    }
+
+    fn add_encodings(&mut self, table : &[ExtractedData<Vec<Inst>>]) {
+        for entry in table.iter() {
+            if let Some((mask, bits)) = entry.data().first().and_then(isa::encoding) {
+                self.encodings.push((entry.key().to_string(), mask, bits));
+            }
+        }
+    }
+
+    pub fn set_xlen(&mut self, xlen : Xlen) {
+        self.xlen = xlen;
+    }
+
+    // Lets li use compressed instructions.
+    pub fn set_compressed(&mut self, compressed : bool) {
+        self.compressed = compressed;
+    }
+
+    // Sets where the program is placed, e.g. 0x8000_0000 for firmware linked into RAM.
+    // Sections without a base of their own follow on from there.
+    pub fn set_base_address(&mut self, base_address : Address) {
+        self.sections.set_start(base_address);
+    }
+
+    // Places a section at a fixed address, e.g. `.data` at 0x8000_0000 with code in ROM at 0.
+    pub fn set_section_base(&mut self, name : &str, base : Address) {
+        self.sections.set_base(name, base);
+    }
+
+    // Every section of the last parse_file, in the order they were first opened.
+    pub fn sections(&self) -> &[Section] {
+        return self.sections.list();
+    }
+
+    // Where the program starts, for the start record of HEX output. The entry is an address or
+    // a symbol. Without one it is `_start` when that is defined, otherwise the base of .text.
+    // None when the entry symbol is not defined.
+    pub fn entry_point(&self, entry : Option<&str>) -> Option<Address> {
+        let symbol = |name : &str| self.symbols.get(name).map(|symbol| symbol.value as Address);
+        if let Some(entry) = entry {
+            return str_to_address(entry).ok().or_else(|| symbol(entry));
         }
+        return symbol("_start").or(self.sections.find(".text").map(|text| text.base));
     }
 
-    // Initializes both hashmaps
+    // Initializes both hashmaps, the directives are added to the instructions
     pub fn initialize(&mut self, extracted_instuctions : &Vec::<ExtractedData<Vec<Inst>>>, extracted_regs : &Vec::<ExtractedData<Reg>>) {
         self.inst_hm.build(extracted_instuctions);
+        self.add_encodings(extracted_instuctions);
+        self.inst_hm.build(&directives::directives());
         self.reg_hm.build(extracted_regs);
         return;
     }
 
-    fn gen_translate(&mut self, new_parsed : &mut ParsedNode, broken_line : &Vec<&str>, shift_arr : Vec<i32>) -> bool {
-        for i in 1..shift_arr.len() {
-            let reg_opt = self.reg_hm.get(broken_line[i]);
-            
-            if reg_opt.is_none() {
-                return false;
+    // Adds pseudo-instructions. They are expanded into the instructions of the table when translated,
+    // so only the ones whose expansion the table can encode will work.
+    pub fn add_pseudo(&mut self, extracted_pseudo : &Vec::<ExtractedData<isa::Pseudo>>) {
+        self.pseudo_hm.build(extracted_pseudo);
+        let markers: Vec<ExtractedData<Vec<Inst>>> = extracted_pseudo.iter()
+            .map(|pseudo| ExtractedData::new(pseudo.key(), vec![Inst::new(InstType::PSEUDO, 0, 0, 0)]))
+            .collect();
+        self.inst_hm.build(&markers);
+    }
+
+    // Location of a token on the line being translated. Tokens that are not on the line, such as the
+    // registers a pseudo-instruction adds, point at the pseudo-instruction or else are looked up.
+    fn loc(&self, token : &str) -> Location {
+        let (file, line) = self.di.locate(self.line_idx);
+        let column = match offset_in(&self.line_text, token) {
+            Some(offset) => offset + 1,
+            None => self.expansion_column
+                .or(self.line_text.find(token).map(|offset| offset + 1))
+                .unwrap_or(1)
+        };
+        return Location { file, line, column };
+    }
+
+    fn check_operands(&self, broken_line : &[&str], expected : usize) -> TranslateResult {
+        if broken_line.len() != expected + 1 {
+            return Err(AssemblerError::WrongOperandCount {
+                loc: self.loc(broken_line[0]),
+                token: broken_line[0].to_string(),
+                expected,
+                found: broken_line.len() - 1
+            });
+        }
+        Ok(())
+    }
+
+    fn reg(&self, token : &str) -> Result<u32, AssemblerError> {
+        return match self.reg_hm.get(token) {
+            Some(reg) => Ok(0b11111 & reg.reg_num),
+            None => Err(AssemblerError::BadRegister { loc: self.loc(token), token: token.to_string() })
+        };
+    }
+
+    // Evaluates an operand expression. `.` is the address of the current instruction.
+    fn eval(&self, token : &str) -> Result<expr::Value, AssemblerError> {
+        return self.eval_at(token, self.curr_address);
+    }
+
+    // Evaluates an expression with `.` standing for the given address.
+    fn eval_at(&self, token : &str, dot : Address) -> Result<expr::Value, AssemblerError> {
+        let lookup = |name : &str| -> Option<expr::Value> {
+            if name == "." {
+                return Some(expr::Value::address(dot as i64));
             }
+            return match self.symbols.get(name) {
+                Some(symbol) => Some(symbol.value()),
+                // Forward references are only known after a layout pass
+                None if self.layout_pass => Some(expr::Value::number(0)),
+                None => None
+            };
+        };
 
-            let reg = reg_opt.unwrap();           
-            new_parsed.instruction |= (0b11111 & reg.reg_num) << shift_arr[i];
+        return expr::eval(token, &lookup).map_err(|err| match err {
+            expr::ExprError::Undefined(name) => {
+                // Located by its place in the expression, which is on the line
+                let part = token.find(name.as_str()).map_or(token, |i| &token[i..i + name.len()]);
+                AssemblerError::UndefinedLabel { loc: self.loc(part), token: name }
+            },
+            expr::ExprError::Invalid(message) => AssemblerError::Syntax {
+                loc: self.loc(token),
+                token: token.to_string(),
+                message: format!("invalid expression `{token}`: {message}")
+            }
+        });
+    }
+
+    // Value of an immediate operand. Labels stand for their address.
+    fn imm(&self, token : &str) -> Result<i64, AssemblerError> {
+        return Ok(self.eval(token)?.val);
+    }
+
+    fn modifier_err(&self, token : &str, message : String) -> AssemblerError {
+        return AssemblerError::Syntax { loc: self.loc(token), token: token.to_string(), message };
+    }
+
+    // The relocation modifier of an operand and its expression, None when it has none.
+    fn modifier<'t>(&self, token : &'t str) -> Result<Option<(&'t str, &'t str)>, AssemblerError> {
+        if !token.starts_with('%') {
+            return Ok(None);
         }
+        return split_modifier(token)
+            .map(Some)
+            .ok_or_else(|| self.modifier_err(token, format!("expected `%modifier(expression)`, found `{token}`")));
+    }
 
-        true
+    // Value of an I or S-type immediate, which may be %lo(value) or %pcrel_lo(label).
+    fn low_imm(&self, token : &str) -> Result<i64, AssemblerError> {
+        let Some((name, inner)) = self.modifier(token)? else {
+            return self.imm(token);
+        };
+
+        return match name.to_ascii_lowercase().as_str() {
+            "lo" => Ok(low12(self.imm(inner)?)),
+            "pcrel_lo" => {
+                // The label is on the auipc, whose %pcrel_hi gives the offset to split
+                let auipc = self.eval(inner)?;
+                if !auipc.is_address() {
+                    return Err(self.modifier_err(token,
+                        format!("`%pcrel_lo` takes the label of an auipc, `{inner}` is not a label")));
+                }
+                let address = auipc.val as Address;
+                let target = self.pcrel_hm.get(&address)
+                    .or(self.prev_pcrel_hm.get(&address))
+                    .cloned();
+                match target {
+                    // A target that does not evaluate is reported on the auipc, not here again
+                    Some(target) => Ok(self.eval_at(&target, address)
+                        .map_or(0, |value| low12(value.val.wrapping_sub(address as i64)))),
+                    None if self.layout_pass => Ok(0),
+                    None => Err(self.modifier_err(token,
+                        format!("`{inner}` does not label an auipc using `%pcrel_hi`")))
+                }
+            },
+            "hi" | "pcrel_hi" => Err(self.modifier_err(token,
+                format!("`%{name}` gives the upper 20 bits, only lui and auipc take it"))),
+            _ => Err(self.modifier_err(token, format!("`%{name}` is not a relocation modifier")))
+        };
     }
 
-    fn rtype(&mut self, new_parsed : &mut ParsedNode, broken_line : &Vec<&str>) -> bool {
-        
-        if broken_line.len() != 4 {
-            return false;
+    // Checks an immediate is within min..=max.
+    fn check_range(&self, token : &str, value : i64, min : i64, max : i64) -> TranslateResult {
+        if value < min || value > max {
+            return Err(AssemblerError::ImmediateOutOfRange {
+                loc: self.loc(token),
+                token: token.to_string(),
+                value,
+                min,
+                max
+            });
         }
-        
+        Ok(())
+    }
+
+    fn gen_translate(&mut self, new_parsed : &mut ParsedNode, broken_line : &[&str], shift_arr : Vec<i32>) -> TranslateResult {
+        for (i, shift) in shift_arr.iter().enumerate().skip(1) {
+            new_parsed.instruction |= self.reg(broken_line[i])? << shift;
+        }
+
+        Ok(())
+    }
+
+    fn rtype(&mut self, new_parsed : &mut ParsedNode, broken_line : &[&str]) -> TranslateResult {
+
+        self.check_operands(broken_line, 3)?;
+
         return self.gen_translate(new_parsed, broken_line, vec![0, 7, 15, 20]);
-        
+
     }
 
     // i-type reg
-    fn itype_regular(&mut self, new_parsed : &mut ParsedNode, broken_line : &Vec<&str>) -> bool{
-        if (new_parsed.instruction & 0x7F) == 0x3 {
-            return false;
-        }
+    fn itype_regular(&mut self, new_parsed : &mut ParsedNode, broken_line : &[&str]) -> TranslateResult {
+        self.check_operands(broken_line, 3)?;
 
-        if self.gen_translate(new_parsed, broken_line, vec![0, 7, 15]) == false {
-            return false;
-        }
-        
-        let trns_int_res = data_structures::str_to_int(broken_line[3]);
-        if trns_int_res.is_err() {
-            return false;
+        self.gen_translate(new_parsed, broken_line, vec![0, 7, 15])?;
+
+        let imm = self.low_imm(broken_line[3])?;
+
+        // Shifts only take a shift amount, funct7 sits in the upper immediate bits
+        let funct3 = (new_parsed.instruction >> 12) & 0b111;
+        if (new_parsed.instruction & 0x7F) == 0x13 && (funct3 == 1 || funct3 == 5) {
+            let max_shamt = match self.xlen {
+                Xlen::Rv32 => 31,
+                Xlen::Rv64 => 63
+            };
+            self.check_range(broken_line[3], imm, 0, max_shamt)?;
+        } else {
+            self.check_range(broken_line[3], imm, -2048, 2047)?;
         }
-        
-        let trns_int : u32 = trns_int_res.unwrap();
-        new_parsed.instruction |=  trns_int  << 20;
+        new_parsed.instruction |= (imm as u32 & 0xFFF) << 20;
+
+        Ok(())
+
+    }
+
+    // Splits a memory operand written as offset(register), or (register) for offset 0.
+    // Returns the 12-bit offset and the register number.
+    fn mem_operand(&self, token : &str) -> Result<(u32, u32), AssemblerError> {
+        let syntax_err = || AssemblerError::Syntax {
+            loc: self.loc(token),
+            token: token.to_string(),
+            message: "expected an operand of the form `offset(register)`".to_string()
+        };
+
+        // The register is the last parenthesised part, the offset may have parentheses of its own
+        let inner = token.strip_suffix(')').ok_or_else(syntax_err)?;
+        let open = unquoted(inner).iter()
+            .rev()
+            .find(|(_, c, depth)| *c == '(' && *depth == 1)
+            .map(|(i, _, _)| *i)
+            .ok_or_else(syntax_err)?;
+        let offset_str = inner[..open].trim();
+        let reg_str = inner[open + 1..].trim();
+
+        let reg = self.reg(reg_str)?;
+        let offset = match offset_str {
+            "" => 0,
+            _ => self.low_imm(offset_str)?
+        };
+        self.check_range(offset_str, offset, -2048, 2047)?;
+
+        return Ok((offset as u32 & 0xFFF, reg));
+    }
+
+    // i-type ld, rd, offset(rs1)
+    fn i_type_ld(&mut self, new_parsed : &mut ParsedNode, broken_line : &[&str]) -> TranslateResult {
+        self.check_operands(broken_line, 2)?;
+
+        new_parsed.instruction |= self.reg(broken_line[1])? << 7;
+
+        let (offset, rs1) = self.mem_operand(broken_line[2])?;
+        new_parsed.instruction |= rs1 << 15;
+        new_parsed.instruction |= offset << 20;
 
-        true
+        Ok(())
+    }
 
+    // i-type system, everything but the opcode and funct12 is zero
+    fn i_type_system(&mut self, broken_line : &[&str]) -> TranslateResult {
+        return self.check_operands(broken_line, 0);
     }
 
-    // i-type ld
-    fn i_type_ld(&mut self, new_parsed : &mut ParsedNode, broken_line : &Vec<&str>) -> bool{
-        if (new_parsed.instruction & 0x7F) != 0x3 {
-            return false;
+    // i-type fence, the predecessor and successor sets are written as letters of "iorw".
+    // A bare fence orders everything.
+    fn i_type_fence(&mut self, new_parsed : &mut ParsedNode, broken_line : &[&str]) -> TranslateResult {
+        if broken_line.len() == 1 {
+            new_parsed.instruction |= 0xFF << 20;
+            return Ok(());
         }
+        self.check_operands(broken_line, 2)?;
 
-        let mut reg_opt = self.reg_hm.get(broken_line[1]);
-        if reg_opt.is_none() {
-            return false;
+        for (i, shift) in [(1, 24), (2, 20)] {
+            let mut set: u32 = 0;
+            for c in broken_line[i].chars() {
+                let bit = match c.to_ascii_lowercase() {
+                    'i' => 0b1000,
+                    'o' => 0b0100,
+                    'r' => 0b0010,
+                    'w' => 0b0001,
+                    _ => return Err(AssemblerError::Syntax {
+                        loc: self.loc(broken_line[i]),
+                        token: broken_line[i].to_string(),
+                        message: format!("`{}` is not a fence set, expected letters from `iorw`", broken_line[i])
+                    })
+                };
+                set |= bit;
+            }
+            new_parsed.instruction |= set << shift;
         }
 
-        let mut reg = reg_opt.unwrap();
-        new_parsed.instruction |= (0b11111 * reg.reg_num) << 7;
-        
-        let last_arg = broken_line[2].to_owned();
-        let last_arg_split = broken_line[2].
+        Ok(())
+    }
 
+    // i-type
+    fn itype(&mut self, new_parsed : &mut ParsedNode, broken_line : &[&str]) -> TranslateResult {
 
-        true
+        // Need to consider several formats for i-type instructions, loads use offset(register).
+        // jalr takes both `rd, rs1, imm` and the `rd, offset(rs1)` of loads.
+        return match new_parsed.instruction & 0x7F {
+            0x03 => self.i_type_ld(new_parsed, broken_line),
+            0x67 if broken_line.len() == 3 => self.i_type_ld(new_parsed, broken_line),
+            0x0F => self.i_type_fence(new_parsed, broken_line),
+            0x73 => self.i_type_system(broken_line),
+            _ => self.itype_regular(new_parsed, broken_line)
+        };
     }
 
+    // s-type, rs2, offset(rs1)
+    fn stype(&mut self, new_parsed : &mut ParsedNode, broken_line : &[&str]) -> TranslateResult {
 
-    // i-type
-    fn itype(&mut self, new_parsed : &mut ParsedNode, broken_line : &Vec<&str>) -> bool {
-        
-        // Need to consider two formats for i-type instructions.
-        if broken_line.len() == 4 {
-            return self.itype_regular(new_parsed, broken_line);
+        self.check_operands(broken_line, 2)?;
 
-        } else if broken_line.len() == 3 {
-            return self.i_type_ld(new_parsed, broken_line);
-        }
+        new_parsed.instruction |= self.reg(broken_line[1])? << 20;
+
+        let (offset, rs1) = self.mem_operand(broken_line[2])?;
+        new_parsed.instruction |= rs1 << 15;
+
+        // S types split the offset as imm[11:5] rs2 rs1 funct3 imm[4:0]
+        new_parsed.instruction |= (offset & 0b11111) << 7;
+        new_parsed.instruction |= (offset >> 5) << 25;
 
-        
-        false
+        Ok(())
     }
-    
-    fn stype(&mut self, new_parsed : &mut ParsedNode, broken_line : &Vec<&str>) -> bool {
-        
-        if broken_line.len() != 3 {
-            return false;
-        }
-        
+
+    // Also similar to S-Type
+    fn btype(&mut self, new_parsed : &mut ParsedNode, broken_line : &[&str]) -> TranslateResult {
+
+        self.check_operands(broken_line, 3)?;
+
+        self.gen_translate(new_parsed, broken_line, vec![0, 15, 20])?;
+
+        let offset = self.pc_offset(broken_line[3])?;
+        self.check_offset(broken_line[3], offset, 13)?;
+
+        // B types split the offset as imm[12|10:5] rs2 rs1 funct3 imm[4:1|11]
+        let imm = offset as u32;
+        new_parsed.instruction |= ((imm >> 12) & 0x1) << 31;
+        new_parsed.instruction |= ((imm >> 5) & 0x3F) << 25;
+        new_parsed.instruction |= ((imm >> 1) & 0xF) << 8;
+        new_parsed.instruction |= ((imm >> 11) & 0x1) << 7;
+
+        Ok(())
+    }
+
+
+    fn utype(&mut self, new_parsed : &mut ParsedNode, broken_line : &[&str]) -> TranslateResult {
+
+        self.check_operands(broken_line, 2)?;
+
         // Adds each of the operators, shofting them over.
-        let shift_arr = [0, 15, 20];
-        for i in [1, 2] {
-            let reg_opt = self.reg_hm.get(broken_line[i]);
-            if reg_opt.is_none() {
-                return false;
+        self.gen_translate(new_parsed, broken_line, vec![0, 7])?;
+
+        // The immediate is the upper 20 bits of the value
+        let is_auipc = (new_parsed.instruction & 0x7F) == 0x17;
+        let token = broken_line[2];
+        let imm = match self.modifier(token)? {
+            Some((name, inner)) => match name.to_ascii_lowercase().as_str() {
+                "hi" => high20(self.imm(inner)?),
+                "pcrel_hi" if is_auipc => {
+                    // Remembered so a %pcrel_lo naming this auipc can add the low part
+                    let offset = self.imm(inner)?.wrapping_sub(self.curr_address as i64);
+                    self.pcrel_hm.insert(self.curr_address, inner.to_string());
+                    high20(offset)
+                },
+                "pcrel_hi" => return Err(self.modifier_err(token,
+                    "`%pcrel_hi` is relative to the instruction, only auipc takes it".to_string())),
+                "lo" | "pcrel_lo" => return Err(self.modifier_err(token,
+                    format!("`%{name}` gives the low 12 bits, only I and S-type immediates take it"))),
+                _ => return Err(self.modifier_err(token, format!("`%{name}` is not a relocation modifier")))
+            },
+            None => {
+                let value = self.eval(token)?;
+                if value.is_address() && is_auipc {
+                    // auipc can take a label, giving the upper part of the offset to it.
+                    // The +0x800 makes up for the sign of the low 12 bits added afterwards.
+                    high20(value.val.wrapping_sub(self.curr_address as i64))
+                } else {
+                    self.check_range(token, value.val, 0, 0xFFFFF)?;
+                    value.val
+                }
             }
+        };
+
+        new_parsed.instruction |= (imm as u32 & 0xFFFFF) << 12;
+
+        Ok(())
+    }
+
+    // Needs to work with both labels and integers.
+    fn jtype(&mut self, new_parsed : &mut ParsedNode, broken_line : &[&str]) -> TranslateResult {
 
-            let reg = reg_opt.unwrap();           
-            new_parsed.instruction |= (0b11111 & reg.reg_num) << shift_arr[i];
+        self.check_operands(broken_line, 2)?;
+
+        self.gen_translate(new_parsed, broken_line, vec![0, 7])?;
+
+        let offset = self.pc_offset(broken_line[2])?;
+        self.check_offset(broken_line[2], offset, 21)?;
+
+        // J types have a weird bit placement, imm[20|10:1|11|19:12]
+        let imm = offset as u32;
+        new_parsed.instruction |= imm & 0x000FF000;
+        new_parsed.instruction |= (imm & 0x000800) << 9;
+        new_parsed.instruction |= (imm & 0x000007FE) << 20;
+        new_parsed.instruction |= (imm & 0x00100000) << 11;
+
+        Ok(())
+    }
+
+    // Byte offset from the current instruction to a branch or jump operand.
+    // Numbers are already offsets, addresses such as labels are turned into one.
+    fn pc_offset(&mut self, arg : &str) -> Result<i64, AssemblerError> {
+        let value = self.eval(arg)?;
+        if value.is_address() {
+            return Ok(value.val.wrapping_sub(self.curr_address as i64));
         }
-        let imm = u32::from_str_radix(broken_line[3], 10).expect("Bad immediate");
-        new_parsed.instruction |= (imm & 0b11111) << 7;
-        new_parsed.instruction |= (imm & 0b111111100000) << 20;
-        
-        return true;
+        return Ok(value.val);
     }
 
-    // Also similar to S-Type
-    fn btype(&mut self, new_parsed : &mut ParsedNode, broken_line : &Vec<&str>) -> bool {
-        
-        if(broken_line.len() != 4) {
-            return false;
+    // Checks a pc-relative offset is even and fits a signed field of `bits` bits.
+    fn check_offset(&self, token : &str, offset : i64, bits : u32) -> TranslateResult {
+        let limit = 1i64 << (bits - 1);
+        if offset < -limit || offset >= limit {
+            return Err(AssemblerError::ImmediateOutOfRange {
+                loc: self.loc(token),
+                token: token.to_string(),
+                value: offset,
+                min: -limit,
+                max: limit - 2
+            });
+        }
+        if offset % BRANCH_ALIGN != 0 {
+            return Err(AssemblerError::MisalignedTarget {
+                loc: self.loc(token),
+                token: token.to_string(),
+                offset,
+                align: BRANCH_ALIGN
+            });
+        }
+        Ok(())
+    }
+
+    fn add_label(&mut self, label : &str) -> TranslateResult {
+        let name = label.strip_suffix(':').unwrap_or(label);
+        return self.define(name, label, SymbolKind::Label, self.curr_address as i64, false);
+    }
+
+    // Defines the symbol name, written at token. Symbols can only be defined once per pass,
+    // unless both definitions are redefinable, in which case the new value is used from then on.
+    pub(crate) fn define(&mut self, name : &str, token : &str, kind : SymbolKind, value : i64, redefinable : bool) -> TranslateResult {
+        // Checked first, a name that is never stored would look like it moved in every pass
+        if !symbols::valid_name(name) {
+            let what = if kind == SymbolKind::Label { "label" } else { "symbol" };
+            return Err(AssemblerError::Syntax {
+                loc: self.loc(token),
+                token: token.to_string(),
+                message: format!("`{name}` is not a valid {what} name")
+            });
         }
-        
-        if self.gen_translate(new_parsed, broken_line, vec![0, 15, 20]) == false {
-            return false;
+        if let Some(existing) = self.symbols.defined(name)
+            && !(existing.redefinable && redefinable) {
+            return Err(AssemblerError::DuplicateLabel {
+                loc: self.loc(token),
+                token: name.to_string(),
+                previous: existing.loc.clone()
+            });
         }
 
-        let imm_res = data_structures::str_to_int(broken_line[3]);
-        if imm_res.is_err() {
-            return false;
+        // A symbol that moved means the layout has not settled. By the last pass all labels must have,
+        // unless an earlier error moved them. Redefinable symbols can take several values in a pass,
+        // so the previous pass says nothing about them.
+        let moved = self.symbols.previous(name).is_none_or(|prev| prev.value != value || prev.kind != kind);
+        if !redefinable && moved {
+            self.layout_changed = true;
+            if kind == SymbolKind::Label && !self.layout_pass && self.errors.is_empty() {
+                return Err(AssemblerError::Syntax {
+                    loc: self.loc(token),
+                    token: token.to_string(),
+                    message: format!("the address of `{name}` did not settle after {MAX_LAYOUT_PASSES} passes")
+                });
+            }
         }
-        
-        let imm : u32 = imm_res.unwrap();
-        new_parsed.instruction |= (imm & 0b11110 | ((imm & 0x800) >> 11)) << 7;
-        new_parsed.instruction |= ((imm & 0x400 >> 1) |  (0b1111100000 & imm)) << 20;
-        
-        return true;
+
+        self.symbols.define(name, Symbol { kind, value, redefinable, loc: self.loc(token) });
+        Ok(())
     }
-    
 
-    fn utype(&mut self, new_parsed : &mut ParsedNode, broken_line : &Vec<&str>) -> bool {
-        
-        // Adds each of the operators, shofting them over.
-        if self.gen_translate(new_parsed, broken_line, vec![0, 7]) == false {
-            return false;
+    // Carries out a directive, see the directives module.
+    fn parse_meta(&mut self, inst_vector : &[Inst], broken_line : &[&str]) -> TranslateResult {
+        return match inst_vector[0].opcode {
+            directives::ORG => self.org(broken_line),
+            directives::BYTE => self.data(broken_line, 1),
+            directives::HALF => self.data(broken_line, 2),
+            directives::WORD => self.data(broken_line, 4),
+            directives::DWORD => self.data(broken_line, 8),
+            directives::ASCII => self.string(broken_line, false),
+            directives::ASCIZ => self.string(broken_line, true),
+            directives::P2ALIGN => self.align(broken_line, true),
+            directives::BALIGN => self.align(broken_line, false),
+            directives::SPACE => self.space(broken_line, true),
+            directives::ZERO => self.space(broken_line, false),
+            directives::FILL => self.fill(broken_line),
+            directives::EQU => self.assign(broken_line, false),
+            directives::SET => self.assign(broken_line, true),
+            directives::SECTION_NAME => self.switch_section(broken_line),
+            directives::SECTION => self.section(broken_line),
+            _ => Err(AssemblerError::UnknownDirective {
+                loc: self.loc(broken_line[0]),
+                token: broken_line[0].to_string()
+            })
+        };
+    }
+
+    // Records an error. Nothing is recorded in layout passes, where labels may be missing.
+    fn report(&mut self, err : AssemblerError) {
+        if !self.layout_pass {
+            self.diagnostics.push(Diagnostic::from(err.clone()));
+            self.errors.push(err);
         }
-        
-        let imm_res = data_structures::str_to_int(broken_line[3]);
-        if imm_res.is_err() {
-            return false;
+    }
+
+    fn warn(&mut self, code : &'static str, token : &str, message : String) {
+        if !self.layout_pass {
+            self.diagnostics.push(Diagnostic::warning(code, self.loc(token), token, message));
         }
-        
-        let imm : u32 = imm_res.unwrap();
-        new_parsed.instruction |= imm & 0xFFFFF000;
-        
-        return true;
     }
-    
-    // Needs to work with both labels and integers.
-    fn jtype(&mut self, new_parsed : &mut ParsedNode, broken_line : &Vec<&str>) -> bool {
-        
-        if broken_line.len() != 3 {
-            return false;
+
+    // Flags instructions that write x0, as their result is thrown away.
+    // The canonical nop (addi x0, x0, 0) is left alone.
+    fn check_discarded(&mut self, new_parsed : &ParsedNode, mnemonic : &str) {
+        let opcode = new_parsed.instruction & 0x7F;
+        let rd = (new_parsed.instruction >> 7) & 0b11111;
+        let writes_rd = matches!(opcode, 0x03 | 0x13 | 0x17 | 0x33 | 0x37);
+
+        if writes_rd && rd == 0 && new_parsed.instruction != 0x13 {
+            self.warn("discarded-result", mnemonic, format!("`{mnemonic}` writes x0, so its result is discarded"));
+        }
+    }
+
+    // Parses a line, breaks the line up into a vector of strings (commas and whitespace used to split)
+    // Determines the instruction type and calls the appropriate command
+    // Errors in the operands are reported and the line still takes up its space,
+    // so that later labels keep the addresses the layout passes gave them.
+    fn parse_line(&mut self, curr_line : &str) -> TranslateResult {
+        let mut broken_line: Vec<&str> = split_line(curr_line);
+
+        if !broken_line.is_empty() && broken_line[0].ends_with(':') {
+            if let Err(err) = self.add_label(broken_line[0]) {
+                self.report(err);
+            }
+            broken_line.remove(0);
         }
 
-        if self.gen_translate(new_parsed, broken_line, vec![0, 7]) == false {
-            return false;
+        // Blank, comment-only and label-only lines
+        if broken_line.is_empty() {
+            return Ok(());
+        }
+        if broken_line[0] == "=" {
+            return self.assign(&broken_line, true);
         }
 
-        let conv_out = data_structures::str_to_int(*(broken_line.last().unwrap()));
-        let imm : u32; 
-        if conv_out.is_err() {
-            let label = *broken_line.last().unwrap();
-            let out = self.label_hm.get(label);
-            if out.is_none() {
-                return false
+        return self.parse_inst(&broken_line, broken_line[0]);
+    }
+
+    // Checks that size bytes at the current address fit in the address space.
+    fn check_address(&self, size : Address, token : &str) -> TranslateResult {
+        if size == 0 {
+            return Ok(());
+        }
+        let last_byte = self.curr_address.checked_add(size - 1);
+        if last_byte.is_none_or(|last| last > self.xlen.max_address()) {
+            // The first byte that does not fit
+            return Err(AssemblerError::AddressOverflow {
+                loc: self.loc(token),
+                token: token.to_string(),
+                address: self.curr_address.max(self.xlen.max_address().saturating_add(1))
+            });
+        }
+        Ok(())
+    }
+
+    // li rd, value, loaded with a lui/addi/shift sequence for the value, see li::sequence.
+    fn load_immediate(&mut self, broken_line : &[&str]) -> TranslateResult {
+        self.check_operands(broken_line, 2)?;
+
+        let rd = self.reg(broken_line[1])?;
+        // A bad value is loaded as 0, which keeps the length the layout passes gave li
+        let value = match self.li_value(broken_line[2]) {
+            Ok(value) => value,
+            Err(err) => {
+                self.report(err);
+                0
             }
-            imm = out.unwrap().clone() as u32;
-        } else {
-            imm = conv_out.unwrap() as u32;
+        };
+
+        let steps = li::sequence(value, self.xlen);
+        for (word, size) in li::encode(&steps, rd, self.xlen, self.compressed) {
+            let new_parsed = ParsedNode { instruction: word, address: self.curr_address, size };
+            self.check_discarded(&new_parsed, broken_line[0]);
+            self.emit(word, size, broken_line[0])?;
         }
+        Ok(())
+    }
 
-        // J types have a weird bit placement
-        new_parsed.instruction |= imm & 0x000FF000;
-        new_parsed.instruction |= (imm & 0x000800) << 9;
-        new_parsed.instruction |= (imm & 0x000007FF) << 20;
-        new_parsed.instruction |= (imm & 0x00100000) << 11;
-        
-        return true;
+    fn li_value(&self, token : &str) -> Result<i64, AssemblerError> {
+        let value = self.imm(token)?;
+        if self.xlen == Xlen::Rv32 {
+            // Either a signed or an unsigned 32-bit value
+            self.check_range(token, value, i32::MIN as i64, u32::MAX as i64)?;
+        }
+        return Ok(value);
     }
 
-    fn add_label(&mut self, label : &str) -> bool {
-        let last_char = label.as_bytes()
-            .last()
-            .expect("Failed to convert to a byte.");
-        
-        if  *last_char != b':' {
-            return false;
+    // Places a word of size bytes at the current address and moves past it.
+    // Sections such as .bss only take up the space.
+    fn emit(&mut self, word : u32, size : u8, token : &str) -> TranslateResult {
+        self.check_address(size as Address, token)?;
+        if self.sections.current().flags.nobits && word != 0 {
+            return Err(self.nobits_err(token, "non-zero data"));
         }
-        
-        if self.label_hm.insert(&self.curr_address, label) == false {
-            return false;
+        if !self.layout_pass && !self.sections.current().flags.nobits {
+            self.di.add_parsed(&ParsedNode { instruction: word, address: self.curr_address, size });
         }
+        self.curr_address = self.curr_address.wrapping_add(size as Address);
+        Ok(())
+    }
 
-        true
+    // Sections such as .bss only reserve space, all they can be given is zeros.
+    fn nobits_err(&self, token : &str, what : &str) -> AssemblerError {
+        return AssemblerError::Syntax {
+            loc: self.loc(token),
+            token: token.to_string(),
+            message: format!("section `{}` only reserves space, it cannot hold {what}", self.sections.current().name)
+        };
     }
 
-    fn parse_meta(&mut self, inst_vector : &Vec<Inst>, broken_line : &Vec<&str>) -> bool{
+    // Expands a pseudo-instruction, putting its operands into the instructions it stands for.
+    // Mnemonic is the one written on the line, which warnings about the expansion name.
+    fn expand_pseudo(&mut self, broken_line : &[&str], mnemonic : &str) -> TranslateResult {
+        let Some(pseudo) = self.pseudo_hm.get(broken_line[0]).cloned() else {
+            return Err(AssemblerError::UnknownMnemonic {
+                loc: self.loc(broken_line[0]),
+                token: broken_line[0].to_string()
+            });
+        };
+        self.check_operands(broken_line, pseudo.operands)?;
 
-        match inst_vector[0].opcode {
-            // Org is one
-            1 => {
+        // The length of li depends on its value, so it has no fixed expansion
+        if broken_line[0].eq_ignore_ascii_case("li") {
+            return self.load_immediate(broken_line);
+        }
 
-            },
-            _=> {
-                return false;
+        // Operands that are exactly `$i` are the user's own tokens, so errors in them point at the line
+        let outer = self.expansion_column.replace(self.loc(broken_line[0]).column);
+        let mut result = Ok(());
+        for template in pseudo.expansion.iter() {
+            let parts = split_line(template);
+            let substituted: Vec<String> = parts.iter()
+                .map(|part| broken_line.iter().enumerate().skip(1)
+                    .fold(part.to_string(), |text, (i, operand)| text.replace(&format!("${i}"), operand)))
+                .collect();
+            let expanded: Vec<&str> = parts.iter().zip(substituted.iter())
+                .map(|(part, text)| match part.strip_prefix('$').and_then(|i| i.parse::<usize>().ok()) {
+                    Some(i) if i > 0 && i < broken_line.len() => broken_line[i],
+                    _ => text.as_str()
+                })
+                .collect();
+            result = self.parse_inst(&expanded, mnemonic);
+            if result.is_err() {
+                break;
             }
         }
-        true
+        self.expansion_column = outer;
+        return result;
     }
 
-    // Parses a line, breaks the line up into a vector of strings (commas and whitespace used to split)
-    // Determines the instruction type and calls the appropriate command
-    // Returns a bool to indicate success
-    fn parse_line(&mut self) -> bool {
-        let line_opt = self.di.pop_line();
-        if line_opt.is_none() {
-            return false;
-        }        
-
-        // Unwraps line object, breaks off any comment, then splits the line without comments.
-        // Comments start with #
-        let curr_line = line_opt.unwrap();
-        let c_vec: Vec<&str> = curr_line.split(|c| c == '#')
-            .collect();
-        
-        let uncommented_line: String = c_vec[0].to_owned();
-        let broken_line: Vec<&str> = uncommented_line.split(|c| c == ',' || c == ' ' || c == '\n')
-            .filter(|s| !(*s).is_empty())
-            .collect();
-
-        if broken_line.len() == 1 && broken_line[0].ends_with(|c| c == ':'){
-            return self.add_label(broken_line[0]);
-        }        
-
+    // Translates an instruction, broken_line holds the mnemonic and its operands.
+    // Mnemonic is the one written on the line, which differs for the parts of a pseudo-instruction.
+    fn parse_inst(&mut self, broken_line : &[&str], mnemonic : &str) -> TranslateResult {
         let inst_opt = self.inst_hm.get(broken_line[0]);
+        if inst_opt.is_none() && broken_line[0].starts_with('.') {
+            return Err(AssemblerError::UnknownDirective {
+                loc: self.loc(broken_line[0]),
+                token: broken_line[0].to_string()
+            });
+        }
         if inst_opt.is_none() {
-            return false;
-        } 
-        
+            return Err(AssemblerError::UnknownMnemonic {
+                loc: self.loc(broken_line[0]),
+                token: broken_line[0].to_string()
+            });
+        }
+
         // Vector of instructions that make up an instruction.
         // This so that this can work for both regular and pseudo-instructions
         let inst_vector = inst_opt.unwrap().clone();
-        
-        if matches!(inst_vector[0].inst_type, InstType::META) {
-            self.parse_meta(&inst_vector, &broken_line);
+
+        match inst_vector[0].inst_type {
+            InstType::META => return self.parse_meta(&inst_vector, broken_line),
+            InstType::PSEUDO => return self.expand_pseudo(broken_line, mnemonic),
+            _ => {}
+        }
+        if self.sections.current().flags.nobits {
+            return Err(self.nobits_err(broken_line[0], "instructions"));
         }
 
         for inst in inst_vector.iter() {
             // Instruction Types, (based on RISC-V Standard)
             let mut new_parsed = ParsedNode{
-                instruction: inst.funct3 | inst.opcode | inst.funct7, 
-                address: self.curr_address
+                instruction: inst.funct3 | inst.opcode | inst.funct7 | inst.funct12,
+                address: self.curr_address,
+                size: INST_SIZE as u8
             };
-            let mut success = false;
-            match inst.inst_type {
+            let result = match inst.inst_type {
                 InstType::R => {
-                    success = self.rtype(&mut new_parsed, &broken_line);
+                    self.rtype(&mut new_parsed, broken_line)
                 },
                 InstType::I=> {
-                    success = self.itype(&mut new_parsed, &broken_line);
+                    self.itype(&mut new_parsed, broken_line)
                 },
                 InstType::S=> {
-                    success = self.stype(&mut new_parsed, &broken_line);
+                    self.stype(&mut new_parsed, broken_line)
                 },
                 InstType::B=> {
-                    success = self.btype(&mut new_parsed, &broken_line);
+                    self.btype(&mut new_parsed, broken_line)
                 },
                 InstType::U=> {
-                    success = self.utype(&mut new_parsed, &broken_line);
+                    self.utype(&mut new_parsed, broken_line)
                 },
                 InstType::J=>{
-                    success = self.jtype(&mut new_parsed, &broken_line);
+                    self.jtype(&mut new_parsed, broken_line)
                 },
                 _=>{
-                    return false;
+                    Err(AssemblerError::UnknownMnemonic {
+                        loc: self.loc(broken_line[0]),
+                        token: broken_line[0].to_string()
+                    })
                 }
+            };
+
+            self.check_address(new_parsed.size as Address, broken_line[0])?;
+
+            match result {
+                Ok(()) => {
+                    self.check_discarded(&new_parsed, mnemonic);
+                    if !self.layout_pass {
+                        self.di.add_parsed(&new_parsed);
+                    }
+                },
+                Err(err) => self.report(err)
             }
-            if success == false {
-                return success;
+            self.curr_address = self.curr_address.wrapping_add(new_parsed.size as Address);
+        }
+
+        return Ok(());
+    }
+
+    // Translates every line in the data interface.
+    // Layout passes place every label so the last pass can resolve forward references.
+    // As li changes length with its value, they run until no label moves.
+    // Translation carries on past errors so they can all be reported together,
+    // but nothing is left in the parsed list if any were found.
+    pub fn parse_file(&mut self) -> Result<(), Vec<AssemblerError>> {
+        let lines: Vec<String> = self.di.lines()
+            .cloned()
+            .collect();
+        self.errors.clear();
+        self.diagnostics.clear();
+        self.symbols.clear();
+        self.sections.clear();
+        self.pcrel_hm.clear();
+
+        for _ in 0..MAX_LAYOUT_PASSES {
+            self.run_pass(&lines, true);
+            if !self.layout_changed {
+                break;
             }
+        }
+        self.run_pass(&lines, false);
+        if self.errors.is_empty() {
+            self.check_overlap();
+        }
 
-            self.di.add_parsed(&new_parsed);
-            self.curr_address += 1;
+        if self.errors.is_empty() {
+            // Sections are filled in turns, the image is laid out by address
+            let mut nodes: Vec<ParsedNode> = Vec::new();
+            while let Some(node) = self.di.pop_parsed() {
+                nodes.push(node);
+            }
+            nodes.sort_by_key(|node| node.address);
+            for node in nodes.iter() {
+                self.di.add_parsed(node);
+            }
+            return Ok(());
         }
 
-        return true;
+        while self.di.pop_parsed().is_some() {}
+        return Err(self.errors.clone());
     }
 
-    pub fn parse_file(&mut self) {
-        let mut line_num: u32 = 1;
+    fn run_pass(&mut self, lines : &[String], layout_pass : bool) {
+        self.layout_pass = layout_pass;
+        self.layout_changed = self.sections.start_pass();
+        self.curr_address = self.sections.current().base;
+        self.symbols.next_pass();
+        self.prev_pcrel_hm = std::mem::take(&mut self.pcrel_hm);
+
+        for (i, line) in lines.iter().enumerate() {
+            self.line_idx = i;
+            self.line_text = Rc::from(line.as_str());
 
-        while self.di.lines_len() > 0 {
-            let success = self.parse_line();
-            if !success {
-                panic!("Failed at line #`{line_num}`");
+            // Parsed from the same text as line_text, so that loc can tell where each token is
+            let text = Rc::clone(&self.line_text);
+            if let Err(err) = self.parse_line(&text) {
+                self.report(err);
             }
-            line_num += 1;
         }
+        if self.sections.end_pass(self.curr_address) {
+            self.layout_changed = true;
+        }
+    }
+
+    // Sections placed at fixed addresses can run into each other.
+    fn check_overlap(&mut self) {
+        let Some((later, earlier)) = self.sections.overlap() else {
+            return;
+        };
+        let err = AssemblerError::Syntax {
+            loc: later.loc.clone(),
+            token: later.name.clone(),
+            message: format!("section `{}` at {:#X}..{:#X} overlaps `{}` at {:#X}..{:#X}",
+                later.name, later.base, later.end(), earlier.name, earlier.base, earlier.end())
+        };
+        self.report(err);
+    }
+
+    // Every error and warning from the last parse_file, in source order.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        return &self.diagnostics;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_fun_call)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[derive(serde::Deserialize)]
+    struct Test<IN, CHK> {
+        test_num : i32,
+        input : IN,
+        check_value : CHK
+    }
+
+    fn load_tests<IN, CHK>(json_name : &str) -> Vec<Test<IN, CHK>> 
+    where 
+        IN: serde::de::DeserializeOwned, // IN must implement deserialize
+        CHK: serde::de::DeserializeOwned, // OUT must implement deserialize
+    {
+        let path = PathBuf::from(std::env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join(json_name);
+
+        let content = fs::read_to_string(&path)
+            .unwrap_or_else(|_| panic!("Failed to read file: {:?}", path));
+        return serde_json::from_str(&content).expect("Failed to parse JSON");
+    }
+
+    fn test_instructions() -> Vec<ExtractedData<Vec<Inst>>> {
+        return vec![
+            ExtractedData::new("addi", vec![Inst::new(InstType::I, 0x13, 0, 0)]),
+            ExtractedData::new("jal", vec![Inst::new(InstType::J, 0x6F, 0, 0)]),
+            ExtractedData::new("beq", vec![Inst::new(InstType::B, 0x63, 0, 0)]),
+            ExtractedData::new("bne", vec![Inst::new(InstType::B, 0x63, 1, 0)]),
+            ExtractedData::new("auipc", vec![Inst::new(InstType::U, 0x17, 0, 0)]),
+        ];
+    }
+
+    fn test_registers() -> Vec<ExtractedData<Reg>> {
+        return (0..32)
+            .map(|num| ExtractedData::new(&format!("x{num}"), Reg { reg_num: num }))
+            .collect();
+    }
+
+    // Assembles the lines with the built-in RV32I table, returning the encoded words.
+    fn assemble_rv32i(lines : &[String]) -> Result<Vec<u32>, Vec<AssemblerError>> {
+        let mut di = DataInterface::new();
+        for line in lines.iter() {
+            di.add_line(line);
+        }
+
+        Translator::with_rv32i(&mut di).parse_file()?;
+        let mut out: Vec<u32> = Vec::new();
+        while let Some(node) = di.pop_parsed() {
+            out.push(node.instruction);
+        }
+        return Ok(out);
+    }
+
+    // Assembles the lines with a small instruction table, returning the parsed nodes.
+    fn assemble(lines : &[String]) -> Result<Vec<ParsedNode>, AssemblerError> {
+        return assemble_at(lines, 0, Xlen::Rv32);
+    }
+
+    fn assemble_at(lines : &[String], base_address : Address, xlen : Xlen) -> Result<Vec<ParsedNode>, AssemblerError> {
+        return assemble_sections(lines, base_address, xlen, &[]);
+    }
+
+    // Assembles the lines with some sections placed at fixed addresses.
+    fn assemble_sections(lines : &[String], base_address : Address, xlen : Xlen, bases : &[(String, Address)]) -> Result<Vec<ParsedNode>, AssemblerError> {
+        let mut di = DataInterface::new();
+        for line in lines.iter() {
+            di.add_line(line);
+        }
+
+        {
+            let mut translator = Translator::new(16, 32, &mut di);
+            translator.initialize(&test_instructions(), &test_registers());
+            translator.set_base_address(base_address);
+            for (name, base) in bases.iter() {
+                translator.set_section_base(name, *base);
+            }
+            translator.set_xlen(xlen);
+            translator.parse_file().map_err(|errors| errors[0].clone())?;
+        }
+
+        let mut out = Vec::new();
+        while let Some(node) = di.pop_parsed() {
+            out.push(node);
+        }
+        return Ok(out);
+    }
+
+    #[test]
+    fn test_two_pass() {
+        let tests: Vec<Test<Vec<String>, Vec<ParsedNode>>> = load_tests("test_two_pass.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let out = assemble(&curr_test.input)
+                .unwrap_or_else(|err| panic!("Test # `{test_num}` failed: {err}"));
+            assert_eq!(out, curr_test.check_value, "Test # `{test_num}` failed.");
+        }
+    }
+
+    #[test]
+    fn test_two_pass_errors() {
+        let tests: Vec<Test<Vec<String>, usize>> = load_tests("test_two_pass_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let err = assemble(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!(err.location().line, curr_test.check_value, "Test # `{test_num}` failed: {err}");
+        }
+    }
+
+    #[test]
+    fn test_pc_relative() {
+        let tests: Vec<Test<Vec<String>, Vec<ParsedNode>>> = load_tests("test_pc_relative.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let out = assemble(&curr_test.input)
+                .unwrap_or_else(|err| panic!("Test # `{test_num}` failed: {err}"));
+            assert_eq!(out, curr_test.check_value, "Test # `{test_num}` failed.");
+        }
+    }
+
+    #[test]
+    fn test_pc_relative_errors() {
+        let tests: Vec<Test<Vec<String>, usize>> = load_tests("test_pc_relative_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let err = assemble(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!(err.location().line, curr_test.check_value, "Test # `{test_num}` failed: {err}");
+        }
+    }
+
+    #[test]
+    fn test_address_space() {
+        let lines: Vec<String> = vec!["start: jal x0, end".to_string(), "end: jal x0, start".to_string()];
+
+        let out = assemble_at(&lines, 0x8000_0000, Xlen::Rv32).expect("Failed to assemble at 0x8000_0000");
+        assert_eq!(out.iter().map(|node| node.address).collect::<Vec<_>>(), vec![0x8000_0000, 0x8000_0004]);
+        assert_eq!(out[0].instruction, 0x0040006F);
+        assert_eq!(out[1].instruction, 0xFFDFF06F);
+
+        // The second instruction would run past the top of a 32-bit address space
+        let err = assemble_at(&lines, 0xFFFF_FFFC, Xlen::Rv32).expect_err("Assembled past 4 GiB");
+        assert!(matches!(err, AssemblerError::AddressOverflow { address: 0x1_0000_0000, .. }));
+        assert_eq!(err.location().line, 2);
+
+        let out = assemble_at(&lines, 0xFFFF_FFFC, Xlen::Rv64).expect("Failed to assemble above 4 GiB");
+        assert_eq!(out[1].address, 0x1_0000_0000);
+    }
+
+    #[test]
+    fn test_error_locations() {
+        let lines: Vec<String> = vec![
+            "start:".to_string(),
+            "    addi x1, x32, 1".to_string(),
+        ];
+        let err = assemble(&lines).expect_err("Bad register accepted");
+        assert_eq!(err, AssemblerError::BadRegister {
+            loc: Location { file: "<input>".to_string(), line: 2, column: 14 },
+            token: "x32".to_string()
+        });
+
+        let lines: Vec<String> = vec!["    jal x1".to_string()];
+        let err = assemble(&lines).expect_err("Missing operand accepted");
+        assert!(matches!(err, AssemblerError::WrongOperandCount { expected: 2, found: 1, .. }));
+        assert_eq!(err.location().column, 5);
+
+        let lines: Vec<String> = vec!["    mul x1, x2, x3".to_string()];
+        let err = assemble(&lines).expect_err("Unknown instruction accepted");
+        assert_eq!(err.token(), "mul");
+
+        // Each token is found where it is, not where its text first shows up on the line
+        let mut di = DataInterface::new();
+        di.add_line(".byte 2560, 256");
+        di.add_line("  beqz x1, %lo(x1)");
+        di.add_line("mv a0, a32");
+        let errors = Translator::with_rv32i(&mut di).parse_file().expect_err("Bad operands accepted");
+        let columns: Vec<(usize, usize)> = errors.iter().map(|err| (err.location().line, err.location().column)).collect();
+        assert_eq!(columns, vec![(1, 7), (1, 13), (2, 13), (3, 8)]);
+    }
+
+    #[test]
+    fn test_collect_errors() {
+        let lines: Vec<String> = vec![
+            "    addi x1, x0, 1".to_string(),
+            "    addi x1, x33, 1".to_string(),
+            "    jal x0, nowhere".to_string(),
+            "    frob x1".to_string(),
+            "    addi x0, x1, 1".to_string(),
+            "end: beq x1, x0, end".to_string(),
+        ];
+        let mut di = DataInterface::new();
+        for line in lines.iter() {
+            di.add_line(line);
+        }
+
+        let mut translator = Translator::new(16, 32, &mut di);
+        translator.initialize(&test_instructions(), &test_registers());
+        let errors = translator.parse_file().expect_err("Errors were not reported");
+        assert_eq!(errors.iter().map(|err| err.location().line).collect::<Vec<_>>(), vec![2, 3, 4]);
+
+        let diagnostics = translator.diagnostics();
+        assert_eq!(diagnostics.len(), 4);
+        assert_eq!(diagnostics[3].severity, Severity::Warning);
+        assert_eq!(diagnostics[3].loc.line, 5);
+        assert_eq!(diagnostics::summary(diagnostics), Some("3 errors, 1 warning".to_string()));
+        drop(translator);
+
+        // No image is produced when anything failed
+        assert_eq!(di.parsed_len(), 0);
+    }
+
+    #[test]
+    fn test_summary() {
+        let warning = Diagnostic::warning("test", Location::default(), "addi", "warning".to_string());
+        let error = Diagnostic::from(AssemblerError::UnknownMnemonic {
+            loc: Location::default(),
+            token: "frob".to_string()
+        });
+
+        assert_eq!(diagnostics::summary(&[]), None);
+        assert_eq!(diagnostics::summary(std::slice::from_ref(&error)), Some("1 error".to_string()));
+        assert_eq!(diagnostics::summary(&[warning.clone(), warning.clone()]), Some("2 warnings".to_string()));
+        assert_eq!(diagnostics::summary(&[error.clone(), error, warning]), Some("2 errors, 1 warning".to_string()));
+    }
+
+    #[test]
+    fn test_render() {
+        let loc = Location { file: "prog.s".to_string(), line: 12, column: 11 };
+        let diag = Diagnostic::from(AssemblerError::BadRegister { loc: loc.clone(), token: "x32".to_string() });
+        assert_eq!(diag.render(Some("\taddi x2, x32, 1"), false),
+            "error: `x32` is not a register\n".to_string()
+            + "  --> prog.s:12:11\n"
+            + "   |\n"
+            + "12 | \taddi x2, x32, 1\n"
+            + "   | \t         ^^^\n"
+            + "   = help: x32 is not a register; valid range is x0–x31\n");
+
+        let diag = Diagnostic::from(AssemblerError::ImmediateOutOfRange {
+            loc, token: "5000".to_string(), value: 5000, min: -2048, max: 2047
+        });
+        assert_eq!(diag.help, Some("immediate 5000 does not fit in 12 signed bits".to_string()));
+
+        // Ranges are described by the fields they fit
+        let help = |value : i64, min : i64, max : i64| Diagnostic::from(AssemblerError::ImmediateOutOfRange {
+            loc: Location::default(), token: String::new(), value, min, max
+        }).help.expect("No help for a range");
+        assert_eq!(help(2560, -128, 255), "immediate 2560 fits in neither 8 signed nor 8 unsigned bits");
+        assert_eq!(help(1 << 32, i32::MIN as i64, u32::MAX as i64), "immediate 4294967296 fits in neither 32 signed nor 32 unsigned bits");
+        assert_eq!(help(4096, -4096, 4094), "immediate 4096 does not fit in 13 signed bits");
+        assert_eq!(help(-1, 0, 0xFFFFF), "immediate -1 does not fit in 20 unsigned bits");
+        assert_eq!(help(9, 0, 8), "immediate 9 must be within 0..=8");
+
+        // Without the source line only the header and help are shown, coloured on request
+        let rendered = diag.render(None, true);
+        assert!(rendered.starts_with("\x1b[1;31merror\x1b[0m"));
+        assert_eq!(rendered.lines().count(), 3);
+        assert!(!diag.render(None, false).contains('\x1b'));
+    }
+
+    #[test]
+    fn test_json() {
+        let loc = Location { file: "prog.s".to_string(), line: 2, column: 10 };
+        let diag = Diagnostic::from(AssemblerError::BadRegister { loc: loc.clone(), token: "x32".to_string() });
+        let json: serde_json::Value = serde_json::from_str(&diag.to_json()).expect("Invalid JSON");
+        assert_eq!(json, serde_json::json!({
+            "severity": "error",
+            "code": "bad-register",
+            "message": "`x32` is not a register",
+            "file": "prog.s",
+            "line": 2,
+            "column_start": 10,
+            "column_end": 13,
+            "suggestion": "x32 is not a register; valid range is x0–x31"
+        }));
+
+        let diag = Diagnostic::warning("discarded-result", loc, "addi", "discarded".to_string());
+        assert!(!diag.to_json().contains('\n'));
+        let json: serde_json::Value = serde_json::from_str(&diag.to_json()).expect("Invalid JSON");
+        assert_eq!(json["severity"], "warning");
+        assert_eq!(json["suggestion"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_rv32i() {
+        let test_vec : Vec<Test<String, u32>> = load_tests("test_rv32i.json");
+
+        for t in test_vec {
+            let test_num = t.test_num;
+            let mut di = DataInterface::new();
+            di.add_line(&t.input);
+            Translator::with_rv32i(&mut di).parse_file()
+                .unwrap_or_else(|errors| panic!("Test #`{test_num}` Failed: {}", errors[0]));
+
+            let node = di.pop_parsed().unwrap_or_else(|| panic!("Test #`{test_num}` Failed :("));
+            assert_eq!(node.instruction, t.check_value, "Test #`{test_num}` Failed :(");
+        }
+
+        // Every base instruction is in the table, stores included
+        let table = isa::rv32i();
+        assert_eq!(table.len(), 40);
+        let mut inst_hm : HashMap<Vec<Inst>> = HashMap::new(64);
+        inst_hm.build(&table);
+        let sw = &inst_hm.get("SW").expect("sw is missing")[0];
+        assert!(matches!(sw.inst_type, InstType::S));
+        assert_eq!(sw.opcode | sw.funct3, 0x2023);
+    }
+
+    #[test]
+    fn test_isa_spec() {
+        let spec = fs::read_to_string(PathBuf::from(std::env!("CARGO_MANIFEST_DIR")).join("tests").join("isa_custom.json"))
+            .expect("Failed to read file");
+        let lines = ["mac x1, x2, x3", "mac.sat x1, x2, x3", "ldx x4, 16(x5)", "clip x6, x7, 255", "halt", "add x1, x2, x3"];
+        let mut di = DataInterface::new();
+        for line in lines.iter() {
+            di.add_line(line);
+        }
+
+        let mut translator = Translator::with_rv32i(&mut di);
+        assert_eq!(translator.load_spec(&spec), Ok(5));
+        translator.parse_file().unwrap_or_else(|errors| panic!("Spec instruction failed: {}", errors[0]));
+        drop(translator);
+
+        let out: Vec<u32> = (0..lines.len()).map(|_| di.pop_parsed().unwrap().instruction).collect();
+        assert_eq!(out, vec![0x0231008B, 0x0431008B, 0x0102E203, 0x0FF3932B, 0x7FF00073, 0x003100B3]);
+    }
+
+    #[test]
+    fn test_isa_spec_errors() {
+        let test_vec : Vec<Test<String, (usize, String)>> = load_tests("test_isa_spec_errors.json");
+
+        for t in test_vec {
+            let test_num = t.test_num;
+            let mut di = DataInterface::new();
+            let err = Translator::with_rv32i(&mut di).load_spec(&t.input)
+                .expect_err(&format!("Test #`{test_num}` Failed, the spec was accepted"));
+            assert_eq!((err.entry, err.message), t.check_value, "Test #`{test_num}` Failed :(");
+        }
+    }
+
+    #[test]
+    fn test_abi_registers() {
+        let lines = ["add ZERO, Ra, sp", "addi a0, fp, 1", "addi s11, T6, 0", "add x8, S0, FP"];
+        let mut di = DataInterface::new();
+        for line in lines.iter() {
+            di.add_line(line);
+        }
+
+        Translator::with_rv32i(&mut di).parse_file()
+            .unwrap_or_else(|errors| panic!("ABI names rejected: {}", errors[0]));
+        let out: Vec<u32> = (0..lines.len()).map(|_| di.pop_parsed().unwrap().instruction).collect();
+        assert_eq!(out, vec![0x00208033, 0x00140513, 0x000F8D93, 0x00840433]);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let test_vec : Vec<Test<u32, (String, String)>> = load_tests("test_disassemble.json");
+
+        for t in test_vec {
+            let test_num = t.test_num;
+            assert_eq!(disasm::disassemble(t.input, disasm::RegNames::Numeric), t.check_value.0,
+                "Test #`{test_num}` Failed :(");
+            assert_eq!(disasm::disassemble(t.input, disasm::RegNames::Abi), t.check_value.1,
+                "Test #`{test_num}` Failed :(");
+        }
+
+        // Disassembly reads back as the same instruction
+        let test_vec : Vec<Test<String, u32>> = load_tests("test_rv32i.json");
+        for t in test_vec {
+            let test_num = t.test_num;
+            for names in [disasm::RegNames::Numeric, disasm::RegNames::Abi] {
+                let mut di = DataInterface::new();
+                di.add_line(&disasm::disassemble(t.check_value, names));
+                Translator::with_rv32i(&mut di).parse_file()
+                    .unwrap_or_else(|errors| panic!("Test #`{test_num}` Failed: {}", errors[0]));
+                assert_eq!(di.pop_parsed().unwrap().instruction, t.check_value, "Test #`{test_num}` Failed :(");
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_store() {
+        let tests: Vec<Test<Vec<String>, Vec<u32>>> = load_tests("test_load_store.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let out = assemble_rv32i(&curr_test.input)
+                .unwrap_or_else(|errors| panic!("Test # `{test_num}` failed: {}", errors[0]));
+            assert_eq!(out, curr_test.check_value, "Test # `{test_num}` failed.");
+        }
+    }
+
+    #[test]
+    fn test_load_store_errors() {
+        let tests: Vec<Test<Vec<String>, (usize, String)>> = load_tests("test_load_store_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let errors = assemble_rv32i(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!((errors[0].location().line, errors[0].code().to_string()), curr_test.check_value,
+                "Test # `{test_num}` failed: {}", errors[0]);
+        }
+    }
+
+    #[test]
+    fn test_immediates() {
+        let test_vec : Vec<Test<String, u32>> = load_tests("test_immediates.json");
+
+        for t in test_vec {
+            let test_num = t.test_num;
+            let out = assemble_rv32i(std::slice::from_ref(&t.input))
+                .unwrap_or_else(|errors| panic!("Test #`{test_num}` Failed: {}", errors[0]));
+            assert_eq!(out, vec![t.check_value], "Test #`{test_num}` Failed :(");
+        }
+    }
+
+    #[test]
+    fn test_immediates_errors() {
+        let test_vec : Vec<Test<String, String>> = load_tests("test_immediates_errors.json");
+
+        for t in test_vec {
+            let test_num = t.test_num;
+            let errors = assemble_rv32i(std::slice::from_ref(&t.input))
+                .expect_err(&format!("Test #`{test_num}` assembled."));
+            assert_eq!(errors[0].code(), t.check_value, "Test #`{test_num}` Failed: {}", errors[0]);
+        }
+    }
+
+    #[test]
+    fn test_relocations() {
+        let tests: Vec<Test<Vec<String>, Vec<u32>>> = load_tests("test_relocations.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let out = assemble_rv32i(&curr_test.input)
+                .unwrap_or_else(|errors| panic!("Test # `{test_num}` failed: {}", errors[0]));
+            assert_eq!(out, curr_test.check_value, "Test # `{test_num}` failed.");
+        }
+    }
+
+    #[test]
+    fn test_relocations_errors() {
+        let tests: Vec<Test<Vec<String>, (usize, String)>> = load_tests("test_relocations_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let errors = assemble_rv32i(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!((errors[0].location().line, errors[0].code().to_string()), curr_test.check_value,
+                "Test # `{test_num}` failed: {}", errors[0]);
+        }
+    }
+
+    #[test]
+    fn test_pseudo() {
+        let tests: Vec<Test<Vec<String>, Vec<String>>> = load_tests("test_pseudo.json");
+
+        // Each pseudo-instruction assembles to the same words as its expansion
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let out = assemble_rv32i(&curr_test.input)
+                .unwrap_or_else(|errors| panic!("Test # `{test_num}` failed: {}", errors[0]));
+            let expected = assemble_rv32i(&curr_test.check_value)
+                .unwrap_or_else(|errors| panic!("Test # `{test_num}` expansion failed: {}", errors[0]));
+            assert_eq!(out, expected, "Test # `{test_num}` failed.");
+        }
+
+        let lines: Vec<String> = vec!["la a0, data".to_string(), "nop".to_string(), "data: mv a1, a0".to_string()];
+        let out = assemble_rv32i(&lines).unwrap_or_else(|errors| panic!("la failed: {}", errors[0]));
+        assert_eq!(out, vec![0x00000517, 0x00C50513, 0x00000013, 0x00050593]);
+
+        // Warnings about an expansion name the pseudo-instruction that was written
+        let mut di = DataInterface::new();
+        di.add_line("  mv x0, x1");
+        let mut translator = Translator::with_rv32i(&mut di);
+        translator.parse_file().expect("mv x0 failed");
+        let diag = &translator.diagnostics()[0];
+        assert_eq!((diag.code, diag.token.as_str(), diag.loc.column), ("discarded-result", "mv", 3));
+        assert_eq!(diag.message, "`mv` writes x0, so its result is discarded");
+    }
+
+    #[test]
+    fn test_pseudo_errors() {
+        let tests: Vec<Test<Vec<String>, (usize, String)>> = load_tests("test_pseudo_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let errors = assemble_rv32i(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!((errors[0].location().line, errors[0].code().to_string()), curr_test.check_value,
+                "Test # `{test_num}` failed: {}", errors[0]);
+        }
+
+        // The undefined label is reported once, on the auipc, not again by the %pcrel_lo after it
+        let errors = assemble_rv32i(&["la a0, nosuch".to_string()]).expect_err("la assembled");
+        assert_eq!(errors.len(), 1, "{errors:?}");
+    }
+
+    #[test]
+    fn test_li() {
+        let test_vec : Vec<Test<String, Vec<u32>>> = load_tests("test_li.json");
+
+        for t in test_vec {
+            let test_num = t.test_num;
+            let out = assemble_rv32i(std::slice::from_ref(&t.input))
+                .unwrap_or_else(|errors| panic!("Test #`{test_num}` Failed: {}", errors[0]));
+            assert_eq!(out, t.check_value, "Test #`{test_num}` Failed :(");
+        }
+
+        let test_vec : Vec<Test<String, String>> = load_tests("test_li_errors.json");
+        for t in test_vec {
+            let test_num = t.test_num;
+            let errors = assemble_rv32i(std::slice::from_ref(&t.input))
+                .expect_err(&format!("Test #`{test_num}` assembled."));
+            assert_eq!(errors[0].code(), t.check_value, "Test #`{test_num}` Failed: {}", errors[0]);
+        }
+
+        assert_eq!(li::sequence(0x7FFFFFFF, Xlen::Rv64), vec![li::Step::Lui(0x80000), li::Step::Addiw(-1)]);
+        assert_eq!(li::sequence(1 << 40, Xlen::Rv64), vec![li::Step::Addi(1), li::Step::Slli(40)]);
+        assert_eq!(li::sequence(0xFFFFFFFF, Xlen::Rv64), vec![li::Step::Addi(-1), li::Step::Srli(32)]);
+        assert_eq!(li::sequence(0x00FF_FFFF_FFFF_FFFF, Xlen::Rv64), vec![li::Step::Addi(-1), li::Step::Srli(8)]);
+
+        // Lengths for values whose shortest sequence is known
+        for (value, len) in [(0, 1), (-1, 1), (2047, 1), (2048, 2), (0x12345678, 2), (i32::MIN as i64, 1),
+            (0x8000_0000, 2), (0x1_0000_0000, 2), (0x1_0000_0001, 3), (0x1234_5678_9ABC_DEF0, 8),
+            (i64::MIN, 2), (i64::MAX, 2), (0x7FFF_FFFF_FFFF_F000, 2)] {
+            let steps = li::sequence(value, Xlen::Rv64);
+            assert_eq!(steps.len(), len, "RV64 li {value:#x} took {steps:?}");
+        }
+    }
+
+    #[test]
+    fn test_li_layout() {
+        // The label after li is only known once li has its length, which depends on the label
+        let lines = ["start: li a0, end", "end: j start"];
+        let mut di = DataInterface::new();
+        for line in lines.iter() {
+            di.add_line(line);
+        }
+        let mut translator = Translator::with_rv32i(&mut di);
+        translator.set_base_address(0x1000);
+        translator.parse_file().unwrap_or_else(|errors| panic!("li failed: {}", errors[0]));
+        drop(translator);
+        let out: Vec<ParsedNode> = (0..3).map(|_| di.pop_parsed().unwrap()).collect();
+        assert_eq!(out.iter().map(|node| node.instruction).collect::<Vec<_>>(), vec![0x00001537, 0x00850513, 0xFF9FF06F]);
+        assert_eq!(out[2].address, 0x1008);
+
+        // Compressed instructions take two bytes
+        let lines = ["li a0, 5", "li a1, 0x1F000", "li a2, 0x20000", "end: j end"];
+        let mut di = DataInterface::new();
+        for line in lines.iter() {
+            di.add_line(line);
+        }
+        let mut translator = Translator::with_rv32i(&mut di);
+        translator.set_compressed(true);
+        translator.parse_file().unwrap_or_else(|errors| panic!("li failed: {}", errors[0]));
+        drop(translator);
+        let out: Vec<ParsedNode> = (0..4).map(|_| di.pop_parsed().unwrap()).collect();
+        assert_eq!(out, vec![
+            ParsedNode { instruction: 0x4515, address: 0, size: 2 },
+            ParsedNode { instruction: 0x65FD, address: 2, size: 2 },
+            ParsedNode { instruction: 0x00020637, address: 4, size: 4 },
+            ParsedNode { instruction: 0x0000006F, address: 8, size: 4 },
+        ]);
+    }
+
+    // Runs the words of a li sequence, giving the value left in the register they write.
+    fn run_li(words : &[(u32, u8)], xlen : Xlen) -> i64 {
+        let sext = |value : u32, bits : u32| ((value as i64) << (64 - bits)) >> (64 - bits);
+        let mut regs = [0i64; 32];
+        let mut rd = 0;
+
+        for (word, size) in words.iter() {
+            let word = *word;
+            rd = ((word >> 7) & 0x1F) as usize;
+            let rs1 = regs[((word >> 15) & 0x1F) as usize];
+            let value = if *size == 2 {
+                let imm = sext((((word >> 12) & 1) << 5) | ((word >> 2) & 0x1F), 6);
+                match (word & 0b11, word >> 13) {
+                    (0b01, 0b010) => imm, // c.li
+                    (0b01, 0b011) => imm << 12, // c.lui
+                    (0b01, 0b000) => regs[rd].wrapping_add(imm), // c.addi
+                    (0b01, 0b001) => regs[rd].wrapping_add(imm) as i32 as i64, // c.addiw
+                    (0b10, 0b000) => regs[rd] << (imm & 0x3F), // c.slli
+                    (0b01, 0b100) if (word >> 10) & 0b11 == 0 => {
+                        rd = 8 + ((word >> 7) & 0b111) as usize;
+                        ((regs[rd] as u64) >> (imm & 0x3F)) as i64 // c.srli
+                    },
+                    _ => panic!("Unexpected compressed instruction {word:#06x}")
+                }
+            } else {
+                match (word & 0x7F, (word >> 12) & 0b111) {
+                    (0x37, _) => sext(word & 0xFFFFF000, 32),
+                    (0x13, 0) => rs1.wrapping_add(sext(word >> 20, 12)),
+                    (0x13, 1) => rs1 << ((word >> 20) & 0x3F),
+                    (0x13, 5) => ((rs1 as u64) >> ((word >> 20) & 0x3F)) as i64,
+                    (0x1B, 0) => rs1.wrapping_add(sext(word >> 20, 12)) as i32 as i64,
+                    _ => panic!("Unexpected instruction {word:#010x}")
+                }
+            };
+            regs[rd] = value;
+            regs[0] = 0;
+        }
+
+        return match xlen {
+            Xlen::Rv32 => regs[rd] as i32 as i64,
+            Xlen::Rv64 => regs[rd]
+        };
+    }
+
+    #[test]
+    fn test_li_values() {
+        let mut values: Vec<i64> = (-4096..=4096).collect();
+        for bit in 0..64 {
+            for delta in [-0x801, -0x800, -0x7FF, -1, 0, 1, 0x7FF, 0x800, 0x801] {
+                values.push((1i64 << bit).wrapping_add(delta));
+                values.push((1i64 << bit).wrapping_neg().wrapping_add(delta));
+            }
+        }
+        values.extend([i64::MIN, i64::MAX, i32::MIN as i64, i32::MAX as i64, u32::MAX as i64]);
+
+        // Pseudo-random values, both full width and with runs of leading sign bits
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        for _ in 0..20000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            values.push(state as i64);
+            values.push((state as i64) >> (state % 64));
+        }
+
+        for value in values {
+            for compressed in [false, true] {
+                let steps = li::sequence(value, Xlen::Rv64);
+                let words = li::encode(&steps, 10, Xlen::Rv64, compressed);
+                assert_eq!(run_li(&words, Xlen::Rv64), value, "RV64 li {value:#x} gave {steps:?}");
+                assert!(steps.len() <= 8, "RV64 li {value:#x} took {steps:?}");
+                if value == value as i32 as i64 {
+                    assert!(steps.len() <= 2, "RV64 li {value:#x} took {steps:?}");
+                }
+
+                let value32 = value as i32 as i64;
+                let steps = li::sequence(value, Xlen::Rv32);
+                let words = li::encode(&steps, 10, Xlen::Rv32, compressed);
+                assert_eq!(run_li(&words, Xlen::Rv32), value32, "RV32 li {value:#x} gave {steps:?}");
+                assert!(steps.len() <= 2, "RV32 li {value:#x} took {steps:?}");
+                if (-2048..2048).contains(&value32) {
+                    assert_eq!(steps.len(), 1, "RV32 li {value:#x} took {steps:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_org() {
+        let tests: Vec<Test<Vec<String>, Vec<ParsedNode>>> = load_tests("test_org.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let out = assemble(&curr_test.input)
+                .unwrap_or_else(|err| panic!("Test # `{test_num}` failed: {err}"));
+            assert_eq!(out, curr_test.check_value, "Test # `{test_num}` failed.");
+        }
+
+        // Numbers are offsets from the base address
+        let lines: Vec<String> = vec![".org 0x10".to_string(), "addi x1, x0, 1".to_string()];
+        let out = assemble_at(&lines, 0x8000_0000, Xlen::Rv32).expect("Failed to assemble .org");
+        assert_eq!(out[0].address, 0x8000_0010);
+    }
+
+    #[test]
+    fn test_org_errors() {
+        let tests: Vec<Test<Vec<String>, (usize, String)>> = load_tests("test_org_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let err = assemble(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!((err.location().line, err.code().to_string()), curr_test.check_value,
+                "Test # `{test_num}` failed: {err}");
+        }
+    }
+
+    #[test]
+    fn test_data() {
+        let tests: Vec<Test<Vec<String>, Vec<ParsedNode>>> = load_tests("test_data.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let out = assemble(&curr_test.input)
+                .unwrap_or_else(|err| panic!("Test # `{test_num}` failed: {err}"));
+            assert_eq!(out, curr_test.check_value, "Test # `{test_num}` failed.");
+        }
+    }
+
+    #[test]
+    fn test_data_errors() {
+        let tests: Vec<Test<Vec<String>, (usize, String)>> = load_tests("test_data_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let err = assemble(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!((err.location().line, err.code().to_string()), curr_test.check_value,
+                "Test # `{test_num}` failed: {err}");
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        let tests: Vec<Test<Vec<String>, Vec<u8>>> = load_tests("test_strings.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let out = assemble(&curr_test.input)
+                .unwrap_or_else(|err| panic!("Test # `{test_num}` failed: {err}"));
+            let bytes: Vec<u8> = out.iter().flat_map(|node| node.bytes()).collect();
+            assert_eq!(bytes, curr_test.check_value, "Test # `{test_num}` failed.");
+        }
+
+        assert_eq!(split_line(r##"msg: .ascii "a, b # c", "d\"#"  # done"##),
+            vec!["msg:", ".ascii", r#""a, b # c""#, r##""d\"#""##]);
+    }
+
+    #[test]
+    fn test_strings_errors() {
+        let tests: Vec<Test<Vec<String>, (usize, String)>> = load_tests("test_strings_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let err = assemble(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!((err.location().line, err.code().to_string()), curr_test.check_value,
+                "Test # `{test_num}` failed: {err}");
+        }
+    }
+
+    #[test]
+    fn test_align() {
+        let tests: Vec<Test<Vec<String>, Vec<ParsedNode>>> = load_tests("test_align.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let out = assemble(&curr_test.input)
+                .unwrap_or_else(|err| panic!("Test # `{test_num}` failed: {err}"));
+            assert_eq!(out, curr_test.check_value, "Test # `{test_num}` failed.");
+        }
+
+        // With compression a c.nop reaches the word boundary
+        let lines = [".byte 1", ".p2align 3", "nop"];
+        let mut di = DataInterface::new();
+        for line in lines.iter() {
+            di.add_line(line);
+        }
+        let mut translator = Translator::with_rv32i(&mut di);
+        translator.set_compressed(true);
+        translator.parse_file().unwrap_or_else(|errors| panic!("Padding failed: {}", errors[0]));
+        drop(translator);
+        let out: Vec<ParsedNode> = (0..5).map(|_| di.pop_parsed().unwrap()).collect();
+        assert_eq!(out, vec![
+            ParsedNode { instruction: 1, address: 0, size: 1 },
+            ParsedNode { instruction: 0, address: 1, size: 1 },
+            ParsedNode { instruction: 0x0001, address: 2, size: 2 },
+            ParsedNode { instruction: 0x00000013, address: 4, size: 4 },
+            ParsedNode { instruction: 0x00000013, address: 8, size: 4 },
+        ]);
+
+        // Space in .bss only moves the counter, however large it is
+        let lines: Vec<String> = [".bss", ".space 0x10000000", ".fill 0x10000000, 8, 0", ".data", ".word 1"].map(String::from).to_vec();
+        let out = assemble(&lines).unwrap_or_else(|err| panic!("Large .bss failed: {err}"));
+        assert_eq!(out, vec![ParsedNode { instruction: 1, address: 0x90000000, size: 4 }]);
+    }
+
+    #[test]
+    fn test_align_errors() {
+        let tests: Vec<Test<Vec<String>, (usize, String)>> = load_tests("test_align_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let err = assemble(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!((err.location().line, err.code().to_string()), curr_test.check_value,
+                "Test # `{test_num}` failed: {err}");
+        }
+    }
+
+    #[test]
+    fn test_equ() {
+        let tests: Vec<Test<Vec<String>, Vec<String>>> = load_tests("test_equ.json");
+
+        // Constants assemble to the same words as their values written out
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let out = assemble_rv32i(&curr_test.input)
+                .unwrap_or_else(|errors| panic!("Test # `{test_num}` failed: {}", errors[0]));
+            let expected = assemble_rv32i(&curr_test.check_value)
+                .unwrap_or_else(|errors| panic!("Test # `{test_num}` expected failed: {}", errors[0]));
+            assert_eq!(out, expected, "Test # `{test_num}` failed.");
+        }
+
+        assert_eq!(split_line("start: n = n * 4 # scale"), vec!["start:", "=", "n", "n * 4"]);
+        assert_eq!(split_line(".word a == b, c <= d"), vec![".word", "a == b", "c <= d"]);
+    }
+
+    #[test]
+    fn test_equ_errors() {
+        let tests: Vec<Test<Vec<String>, (usize, String)>> = load_tests("test_equ_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let errors = assemble_rv32i(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!((errors[0].location().line, errors[0].code().to_string()), curr_test.check_value,
+                "Test # `{test_num}` failed: {}", errors[0]);
+        }
+
+        // A name that cannot be stored is reported as such, not as a label that never settles
+        let errors = assemble_rv32i(&["1:".to_string(), "    nop".to_string()]).expect_err("`1:` assembled");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "`1` is not a valid label name");
+
+        // Redefinitions point back at the first definition
+        let mut di = DataInterface::new();
+        di.add_file("prog.s");
+        di.add_line("  .equ SIZE, 4");
+        di.add_line(".equ SIZE, 8");
+        let mut translator = Translator::with_rv32i(&mut di);
+        translator.parse_file().expect_err("SIZE was redefined");
+        assert_eq!(translator.diagnostics()[0].help.as_deref(),
+            Some("`SIZE` was first defined at prog.s:1:8, only .set and `=` can change a symbol"));
+    }
+
+    #[test]
+    fn test_sections() {
+        // Input is the lines and the sections placed at fixed addresses
+        type Input = (Vec<String>, Vec<(String, Address)>);
+        let tests: Vec<Test<Input, Vec<ParsedNode>>> = load_tests("test_sections.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let (lines, bases) = &curr_test.input;
+            let out = assemble_sections(lines, 0, Xlen::Rv32, bases)
+                .unwrap_or_else(|err| panic!("Test # `{test_num}` failed: {err}"));
+            assert_eq!(out, curr_test.check_value, "Test # `{test_num}` failed.");
+        }
+
+        let mut di = DataInterface::new();
+        for line in ["addi x1, x0, 1", ".bss", ".space 64", ".section .data, \"aw\"", ".word 1", ".data", ".section .data, \"a\""] {
+            di.add_line(line);
+        }
+        let mut translator = Translator::new(16, 32, &mut di);
+        translator.initialize(&test_instructions(), &test_registers());
+        translator.set_base_address(0x100);
+        translator.parse_file().expect("Sections failed");
+
+        let layout: Vec<(&str, Address, Address)> = translator.sections().iter()
+            .map(|section| (section.name.as_str(), section.base, section.size))
+            .collect();
+        assert_eq!(layout, vec![(".text", 0x100, 4), (".bss", 0x104, 64), (".data", 0x144, 4)]);
+        assert!(translator.sections()[1].flags.nobits && translator.sections()[2].flags.write);
+        // Reopening a section with other flags keeps the ones it had
+        assert_eq!(translator.diagnostics().len(), 1);
+        assert_eq!((translator.diagnostics()[0].code, translator.diagnostics()[0].loc.line), ("section-flags", 7));
+
+        // The entry point is .text, not the lowest address, unless _start or an entry says otherwise
+        let mut di = DataInterface::new();
+        for line in [".data", ".word 1", ".text", "nop", "main: nop"] {
+            di.add_line(line);
+        }
+        let mut translator = Translator::with_rv32i(&mut di);
+        translator.set_base_address(0x1000);
+        translator.set_section_base(".data", 0);
+        translator.parse_file().expect("Sections failed");
+        assert_eq!(translator.entry_point(None), Some(0x1000));
+        assert_eq!(translator.entry_point(Some("main")), Some(0x1004));
+        assert_eq!(translator.entry_point(Some("0x2000")), Some(0x2000));
+        assert_eq!(translator.entry_point(Some("nowhere")), None);
+        drop(translator);
+
+        let mut di = DataInterface::new();
+        for line in ["nop", "_start: nop"] {
+            di.add_line(line);
+        }
+        let mut translator = Translator::with_rv32i(&mut di);
+        translator.parse_file().expect("Sections failed");
+        assert_eq!(translator.entry_point(None), Some(4));
+    }
+
+    #[test]
+    fn test_sections_errors() {
+        let tests: Vec<Test<Vec<String>, (usize, String)>> = load_tests("test_sections_errors.json");
+
+        for curr_test in tests.iter() {
+            let test_num = curr_test.test_num;
+            let err = assemble(&curr_test.input)
+                .expect_err(&format!("Test # `{test_num}` assembled."));
+            assert_eq!((err.location().line, err.code().to_string()), curr_test.check_value,
+                "Test # `{test_num}` failed: {err}");
+        }
+
+        // Sections placed at fixed addresses must not run into each other
+        let lines: Vec<String> = ["addi x1, x0, 1", "addi x2, x0, 2", ".data", ".word 1"].map(String::from).to_vec();
+        let err = assemble_sections(&lines, 0, Xlen::Rv32, &[(".data".to_string(), 4)])
+            .expect_err("Overlapping sections assembled");
+        assert_eq!((err.location().line, err.code()), (3, "syntax"));
+        assert!(err.message().contains("overlaps `.text`"), "{err}");
+    }
+
+    fn test_symbols(name : &str) -> Option<expr::Value> {
+        return match name {
+            "BUF_SIZE" => Some(expr::Value::number(64)),
+            "start" => Some(expr::Value::address(100)),
+            "end" => Some(expr::Value::address(124)),
+            _ => None
+        };
+    }
+
+    #[test]
+    fn test_expressions() {
+        let test_vec : Vec<Test<String, i64>> = load_tests("test_expressions.json");
+
+        for t in test_vec {
+            let test_num = t.test_num;
+            let value = expr::eval(&t.input, &test_symbols)
+                .unwrap_or_else(|err| panic!("Test #`{test_num}` Failed: {err:?}"));
+            assert_eq!(value, expr::Value::number(t.check_value), "Test #`{test_num}` Failed :(");
+        }
+
+        assert_eq!(expr::eval("start + 4", &test_symbols), Ok(expr::Value::address(104)));
+        assert_eq!(expr::eval("4 + end - (end - start)", &test_symbols), Ok(expr::Value::address(104)));
+    }
+
+    #[test]
+    fn test_expressions_errors() {
+        let test_vec : Vec<Test<String, String>> = load_tests("test_expressions_errors.json");
+
+        for t in test_vec {
+            let test_num = t.test_num;
+            let kind = match expr::eval(&t.input, &test_symbols) {
+                Ok(value) => panic!("Test #`{test_num}` evaluated to {value:?}"),
+                Err(expr::ExprError::Undefined(_)) => "undefined",
+                Err(expr::ExprError::Invalid(_)) => "invalid"
+            };
+            assert_eq!(kind, t.check_value, "Test #`{test_num}` Failed :(");
+        }
+    }
+
+    #[test]
+    fn test_expression_operands() {
+        let lines: Vec<String> = vec![
+            "start: addi a0, a0, 16*4 - 1".to_string(),
+            "    addi a1, x0, ','  # a comma".to_string(),
+            "    addi a2, x0, '#'".to_string(),
+            "    lw t0, (end - start)(sp)".to_string(),
+            "    sw t0, -(2 + 2)(sp)".to_string(),
+            "    beq x0, x0, start + 4".to_string(),
+            "    jal x0, . - 4".to_string(),
+            "end: addi x0, x0, (1 << 3) | 1".to_string(),
+        ];
+        let out = assemble_rv32i(&lines)
+            .unwrap_or_else(|errors| panic!("Expressions rejected: {}", errors[0]));
+        assert_eq!(out, vec![0x03F50513, 0x02C00593, 0x02300613, 0x01C12283, 0xFE512E23, 0xFE0008E3, 0xFFDFF06F, 0x00900013]);
+    }
+}
@@ -0,0 +1,216 @@
+use data_structures::*;
+
+// Sections holding code start on an instruction boundary
+const CODE_ALIGN : Address = 4;
+
+// Attributes of a section, the flags and type of .section.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SectionFlags {
+    pub alloc: bool, // a, takes up memory when the program runs
+    pub write: bool, // w
+    pub exec: bool, // x, holds code, so alignment pads it with nops
+    pub nobits: bool // @nobits, only reserves space and nothing is emitted for it, like .bss
+}
+
+impl SectionFlags {
+    // Flags of a section opened without any, decided by its name as GNU as does.
+    // `.text.start` gets the flags of `.text`, names that are not known get none.
+    pub fn for_name(name : &str) -> Self {
+        let is = |prefix : &str| name == prefix || name.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('.'));
+        let flags = |write, exec, nobits| Self { alloc: true, write, exec, nobits };
+
+        if is(".text") {
+            return flags(false, true, false);
+        }
+        if is(".data") || is(".sdata") {
+            return flags(true, false, false);
+        }
+        if is(".rodata") || is(".srodata") {
+            return flags(false, false, false);
+        }
+        if is(".bss") || is(".sbss") {
+            return flags(true, false, true);
+        }
+        return Self::default();
+    }
+
+    // Flags of `.section name, "flags", @type`. The type is @progbits or @nobits,
+    // when it is left out the name decides it.
+    pub fn parse(name : &str, flags : &str, kind : Option<&str>) -> Result<Self, String> {
+        let body = flags.strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+            .ok_or_else(|| format!("expected section flags in double quotes, found `{flags}`"))?;
+
+        let mut out = Self { nobits: Self::for_name(name).nobits, ..Self::default() };
+        for c in body.chars() {
+            match c {
+                'a' => out.alloc = true,
+                'w' => out.write = true,
+                'x' => out.exec = true,
+                _ => return Err(format!("unknown section flag `{c}`, expected a, w or x"))
+            }
+        }
+
+        out.nobits = match kind.map(|kind| kind.trim_start_matches(['@', '%'])) {
+            Some("progbits") => false,
+            Some("nobits") => true,
+            Some(_) => return Err(format!("unknown section type `{}`, expected @progbits or @nobits", kind.unwrap_or_default())),
+            None => out.nobits
+        };
+        return Ok(out);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub flags: SectionFlags,
+    pub base: Address, // Address of its first byte
+    pub size: Address, // Bytes from the base to the furthest the location counter got
+    pub align: Address, // Largest alignment asked for in it, its base is kept a multiple of it
+    pub loc: Location, // Where it was first opened, the default .text has no location
+    counter: Address, // Location counter, kept here while another section is being filled
+    prev_size: Address // Size in the previous pass
+}
+
+impl Section {
+    fn new(name : &str, flags : SectionFlags, base : Address, loc : Location) -> Self {
+        let align = if flags.exec { CODE_ALIGN } else { 1 };
+        Self { name: name.to_string(), flags, base, size: 0, align, loc, counter: base, prev_size: 0 }
+    }
+
+    // First address past the section.
+    pub fn end(&self) -> Address {
+        return self.base.saturating_add(self.size);
+    }
+}
+
+// Every section in the order they were first opened, with the one being filled.
+// Sections with a configured base are placed there, the rest follow the section before them.
+pub struct Sections {
+    list: Vec<Section>,
+    current: usize,
+    bases: Vec<(String, Address)>, // Configured base addresses
+    start: Address // Where the first section goes when it has no configured base
+}
+
+impl Default for Sections {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl Sections {
+    pub fn new() -> Self {
+        let mut sections = Self { list: Vec::new(), current: 0, bases: Vec::new(), start: 0 };
+        sections.clear();
+        return sections;
+    }
+
+    // Forgets every section but the default .text, the configured bases are kept.
+    pub fn clear(&mut self) {
+        self.list = vec![Section::new(".text", SectionFlags::for_name(".text"), self.start, Location::default())];
+        self.current = 0;
+    }
+
+    // Where the first section goes, unless it has a base of its own.
+    pub fn set_start(&mut self, start : Address) {
+        self.start = start;
+    }
+
+    // Places the named section at a fixed address.
+    pub fn set_base(&mut self, name : &str, base : Address) {
+        self.bases.retain(|(other, _)| other != name);
+        self.bases.push((name.to_string(), base));
+    }
+
+    fn configured_base(&self, name : &str) -> Option<Address> {
+        return self.bases.iter().find(|(other, _)| other == name).map(|(_, base)| *base);
+    }
+
+    // Base of a section placed after one that ends at end, rounded up to its alignment.
+    fn base_after(&self, end : Address, name : &str, align : Address) -> Address {
+        if let Some(base) = self.configured_base(name) {
+            return base;
+        }
+        return end.checked_next_multiple_of(align).unwrap_or(end);
+    }
+
+    // Starts a pass in the first section. Sections are laid out with the sizes of the previous pass,
+    // returns whether any of them moved.
+    pub fn start_pass(&mut self) -> bool {
+        let mut moved = false;
+        let mut end = self.start;
+        for i in 0..self.list.len() {
+            let base = self.base_after(end, &self.list[i].name, self.list[i].align);
+            let section = &mut self.list[i];
+            moved |= section.base != base;
+            section.base = base;
+            section.counter = base;
+            section.prev_size = section.size;
+            section.size = 0;
+            end = base.saturating_add(section.prev_size);
+        }
+        self.current = 0;
+        return moved;
+    }
+
+    // Ends a pass with the counter of the current section, returns whether any section changed size.
+    pub fn end_pass(&mut self, counter : Address) -> bool {
+        self.save(counter);
+        return self.list.iter().any(|section| section.size != section.prev_size);
+    }
+
+    fn save(&mut self, counter : Address) {
+        let section = &mut self.list[self.current];
+        section.counter = counter;
+        section.size = section.size.max(counter.saturating_sub(section.base));
+    }
+
+    pub fn current(&self) -> &Section {
+        return &self.list[self.current];
+    }
+
+    // Raises the alignment of the current section, so it is not lost when the section is placed.
+    pub fn align_current(&mut self, align : Address) {
+        let section = &mut self.list[self.current];
+        section.align = section.align.max(align);
+    }
+
+    // Switches to the named section, opening it with the given flags if it is new.
+    // Takes the counter of the section being left and gives the one of the section entered.
+    pub fn switch(&mut self, name : &str, flags : SectionFlags, loc : Location, counter : Address) -> Address {
+        self.save(counter);
+        self.current = match self.list.iter().position(|section| section.name == name) {
+            Some(i) => i,
+            None => {
+                let mut section = Section::new(name, flags, 0, loc);
+                section.base = self.base_after(self.list[self.list.len() - 1].end(), name, section.align);
+                section.counter = section.base;
+                self.list.push(section);
+                self.list.len() - 1
+            }
+        };
+        return self.list[self.current].counter;
+    }
+
+    pub fn find(&self, name : &str) -> Option<&Section> {
+        return self.list.iter().find(|section| section.name == name);
+    }
+
+    pub fn list(&self) -> &[Section] {
+        return &self.list;
+    }
+
+    // The first pair of sections that share addresses, the later one first. Empty sections overlap nothing.
+    pub fn overlap(&self) -> Option<(&Section, &Section)> {
+        for (i, later) in self.list.iter().enumerate() {
+            let earlier = self.list[..i].iter()
+                .find(|other| later.size > 0 && other.size > 0 && later.base < other.end() && other.base < later.end());
+            if let Some(earlier) = earlier {
+                return Some((later, earlier));
+            }
+        }
+        return None;
+    }
+}
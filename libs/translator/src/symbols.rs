@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use data_structures::*;
+use crate::expr;
+
+// What defined a symbol, which decides what it stands for in an expression.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymbolKind {
+    Label, // `name:`, the address it is placed at
+    Absolute, // Constant with a plain number, e.g. `.equ SIZE, 16`
+    Relative // Constant holding an address, e.g. `.equ end, . + 4`, it moves with the code
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub value: i64,
+    pub redefinable: bool, // Defined with .set or `=`, so it can be given a new value
+    pub loc: Location // Where it was defined
+}
+
+impl Symbol {
+    // What the symbol stands for in an expression.
+    pub fn value(&self) -> expr::Value {
+        return match self.kind {
+            SymbolKind::Absolute => expr::Value::number(self.value),
+            SymbolKind::Label | SymbolKind::Relative => expr::Value::address(self.value)
+        };
+    }
+}
+
+// Whether a name can be given to a symbol: letters, digits, `_`, `.` and `$`, not starting with a digit.
+pub fn valid_name(name : &str) -> bool {
+    return name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$');
+}
+
+// Labels and constants. Every pass defines them again, those of the previous pass are kept
+// to resolve forward references. Unlike mnemonics and registers, names are case-sensitive.
+pub struct SymbolTable {
+    symbols: HashMap<String, Symbol>, // Defined so far in the current pass
+    prev_symbols: HashMap<String, Symbol> // Everything defined in the previous pass
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self {
+            symbols: HashMap::new(),
+            prev_symbols: HashMap::new()
+        }
+    }
+
+    // Forgets every symbol, including those of the previous pass.
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    // Starts a pass, the symbols defined so far become the previous pass.
+    pub fn next_pass(&mut self) {
+        self.prev_symbols = std::mem::take(&mut self.symbols);
+    }
+
+    // Symbols defined earlier in this pass take priority over the previous pass.
+    pub fn get(&self, name : &str) -> Option<&Symbol> {
+        return self.symbols.get(name).or(self.prev_symbols.get(name));
+    }
+
+    // The symbol if it was defined earlier in this pass.
+    pub fn defined(&self, name : &str) -> Option<&Symbol> {
+        return self.symbols.get(name);
+    }
+
+    // The symbol as the previous pass left it.
+    pub fn previous(&self, name : &str) -> Option<&Symbol> {
+        return self.prev_symbols.get(name);
+    }
+
+    // Defines a symbol, or gives a redefinable one its new value.
+    // Whether the name is valid and whether it may be redefined is up to the caller.
+    pub fn define(&mut self, name : &str, symbol : Symbol) {
+        self.symbols.insert(name.to_string(), symbol);
+    }
+}